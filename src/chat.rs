@@ -0,0 +1,214 @@
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a chat message is, which decides how it is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageKind {
+    /// Text said by the sender
+    Text,
+    /// An action done by the sender, typed as `/me waves`
+    Emote,
+}
+
+/// A message sent to a room or a direct conversation, published over gossipsub as CBOR.
+///
+/// Messages do not carry a username. The sender is the peer which signed the gossipsub message,
+/// and is shown with the username that peer published, so nobody can send messages as someone else.
+/// Messages are also signed by their sender themselves, so they can be checked when they are passed
+/// on by other peers, such as when syncing a room's history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// Unique ID of the message, hex encoded
+    pub id: String,
+    /// Peer ID of the sender
+    pub sender: String,
+    /// Room or direct conversation the message was sent to, so it cannot be replayed into another
+    #[serde(default)]
+    pub topic: String,
+    /// Milliseconds since the Unix epoch when the message was sent, by the sender's clock
+    pub timestamp: u64,
+    pub kind: MessageKind,
+    pub body: String,
+    /// Signature of the other fields by the sender's key
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+impl ChatMessage {
+    /// Creates a message from this peer to a topic, with a new ID, signed with its key.
+    pub fn new(keypair: &Keypair, topic: &str, kind: MessageKind, body: String) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let sender = keypair.public().to_peer_id().to_string();
+
+        // The sender and the exact time are enough to tell apart messages with the same body
+        let mut hasher = Sha256::new();
+        hasher.update(sender.as_bytes());
+        hasher.update(now.as_nanos().to_be_bytes());
+        hasher.update(body.as_bytes());
+        let id = hex::encode(&hasher.finalize()[..16]);
+
+        let mut message = Self {
+            id,
+            sender,
+            topic: topic.to_string(),
+            timestamp: now.as_millis() as u64,
+            kind,
+            body,
+            signature: Vec::new(),
+        };
+        message.signature = keypair.sign(&message.signed_bytes()).unwrap_or_default();
+        message
+    }
+
+    /// Creates a message from text typed into the input box, which is an emote if it starts with `/me `.
+    pub fn from_input(keypair: &Keypair, topic: &str, input: &str) -> Self {
+        match input.strip_prefix("/me ") {
+            Some(action) => Self::new(keypair, topic, MessageKind::Emote, action.to_string()),
+            None => Self::new(keypair, topic, MessageKind::Text, input.to_string()),
+        }
+    }
+
+    /// Returns true if the message was signed by its sender, whose public key is part of their peer ID.
+    pub fn verify(&self) -> bool {
+        self.sender.parse::<PeerId>().ok()
+            .and_then(|peer_id| peer_public_key(&peer_id))
+            .is_some_and(|key| key.verify(&self.signed_bytes(), &self.signature))
+    }
+
+    // Encodes the fields covered by the signature
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_cbor::to_vec(&(&self.id, &self.sender, &self.topic, self.timestamp, self.kind, &self.body)).unwrap_or_default()
+    }
+
+    /// Encodes the message to be published.
+    pub fn encode(&self) -> Result<Vec<u8>, serde_cbor::Error> {
+        serde_cbor::to_vec(self)
+    }
+
+    /// Decodes a received message.
+    pub fn decode(bytes: &[u8]) -> Result<Self, serde_cbor::Error> {
+        serde_cbor::from_slice(bytes)
+    }
+
+    /// Returns the message as shown in a conversation, with the name of its sender.
+    pub fn display(&self, sender_name: &str) -> String {
+        match self.kind {
+            MessageKind::Text => format!("{}: {}", sender_name, self.body),
+            MessageKind::Emote => format!("* {} {}", sender_name, self.body),
+        }
+    }
+}
+
+/// Returns the public key of a peer, which is held in its peer ID rather than hashed for small
+/// keys such as the Ed25519 keys used here.
+pub fn peer_public_key(peer_id: &PeerId) -> Option<PublicKey> {
+    let multihash = peer_id.as_ref();
+    if multihash.code() != 0 {
+        return None;
+    }
+    PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
+
+/// Returns the topic of the direct conversation between two peers, named after both their peer IDs.
+pub fn direct_topic(a: &PeerId, b: &PeerId) -> String {
+    let mut peer_ids = [a.to_string(), b.to_string()];
+    peer_ids.sort();
+    peer_ids.join("_")
+}
+
+/// How far a direct message sent by this peer has got towards the other peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryState {
+    /// The other peer could not be reached, so the message waits to be sent when they reappear
+    Queued,
+    /// The message waits to be sent, and is also stored in the DHT for the other peer to pick up
+    Parked,
+    /// The message was published to the other peer
+    Sent,
+}
+
+/// Largest number of messages sent in reply to a history request.
+pub const MAX_SYNC_MESSAGES: usize = 500;
+
+/// Asks a member of a room for the messages sent to it recently, over the `/chat-history/1` protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub room: String,
+    /// Milliseconds since the Unix epoch, before which messages are not wanted
+    pub since: u64,
+    /// Most messages wanted, the newest being sent if there are more
+    pub limit: usize,
+}
+
+/// Messages sent to a room, oldest first, in reply to a `HistoryRequest`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryResponse {
+    pub room: String,
+    pub messages: Vec<ChatMessage>,
+}
+
+impl HistoryResponse {
+    /// Returns the messages of the response which answer a request, or `None` if it is for another room.
+    ///
+    /// Only messages signed by their sender for the requested room, and no older than asked for, are
+    /// kept, so members cannot replay messages from elsewhere into the room.
+    pub fn accepted(self, request: &HistoryRequest) -> Option<Vec<ChatMessage>> {
+        if self.room != request.room {
+            return None;
+        }
+        let messages = self.messages.into_iter()
+            .take(request.limit.min(MAX_SYNC_MESSAGES))
+            .filter(|message| message.topic == request.room && message.timestamp >= request.since && message.verify())
+            .collect();
+        Some(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_messages_signed_by_their_sender() {
+        let keypair = Keypair::generate_ed25519();
+        let message = ChatMessage::from_input(&keypair, "general", "/me waves");
+        assert_eq!(message.kind, MessageKind::Emote);
+        assert!(message.verify());
+        assert!(ChatMessage::decode(&message.encode().unwrap()).unwrap().verify());
+
+        // Moving the message to another room or changing it breaks the signature
+        let forged = ChatMessage { topic: "engineering".to_string(), ..message.clone() };
+        assert!(!forged.verify());
+        let forged = ChatMessage { body: "leaves".to_string(), ..message.clone() };
+        assert!(!forged.verify());
+
+        // As does claiming it came from another peer
+        let other = Keypair::generate_ed25519().public().to_peer_id().to_string();
+        let forged = ChatMessage { sender: other, ..message.clone() };
+        assert!(!forged.verify());
+        let unsigned = ChatMessage { signature: Vec::new(), ..message };
+        assert!(!unsigned.verify());
+    }
+
+    #[test]
+    fn accepts_synced_messages_of_the_requested_room_only() {
+        let keypair = Keypair::generate_ed25519();
+        let request = HistoryRequest { room: "general".to_string(), since: 1000, limit: 10 };
+        let message = |topic: &str, timestamp| ChatMessage { timestamp, ..ChatMessage::from_input(&keypair, topic, "hi") };
+        let resign = |message: ChatMessage| ChatMessage { signature: keypair.sign(&message.signed_bytes()).unwrap(), ..message };
+
+        let recent = resign(message("general", 2000));
+        let old = resign(message("general", 500));
+        let elsewhere = message("engineering", 2000);
+        let tampered = message("general", 2000);
+        let messages = vec![recent.clone(), old, elsewhere, tampered];
+
+        let response = HistoryResponse { room: "general".to_string(), messages: messages.clone() };
+        assert_eq!(response.accepted(&request), Some(vec![recent]));
+        let response = HistoryResponse { room: "engineering".to_string(), messages };
+        assert_eq!(response.accepted(&request), None);
+    }
+}
@@ -0,0 +1,234 @@
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use crate::chat::ChatMessage;
+use crate::history;
+use crate::logger;
+
+/// How often messages past their retention are dropped while running.
+pub const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message as recorded in the chat log, with the room or direct conversation it was sent to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LoggedMessage {
+    topic: String,
+    message: ChatMessage,
+}
+
+/// How long the messages of matching rooms are kept for.
+///
+/// Rules are written as comma separated conditions, for example `room=engineering,max-age=7d,max-count=1000`.
+#[derive(Debug, Clone)]
+pub struct RetentionRule {
+    /// Glob the room name, or the topic of a direct conversation, must match
+    pub room: Pattern,
+    /// Oldest message kept, in seconds
+    pub max_age: Option<u64>,
+    /// Most messages kept, dropping the oldest first
+    pub max_count: Option<usize>,
+}
+
+impl RetentionRule {
+    // Keeps the messages of a room allowed by the rule, given the current time in seconds since the Unix epoch
+    fn apply(&self, messages: &mut Vec<ChatMessage>, now: u64) {
+        if let Some(max_age) = self.max_age {
+            let oldest = now.saturating_sub(max_age).saturating_mul(1000);
+            messages.retain(|message| message.timestamp >= oldest);
+        }
+        if let Some(max_count) = self.max_count {
+            let excess = messages.len().saturating_sub(max_count);
+            messages.drain(..excess);
+        }
+    }
+}
+
+impl FromStr for RetentionRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (mut room, mut max_age, mut max_count) = (None, None, None);
+        for condition in rule.split(',').map(str::trim).filter(|condition| !condition.is_empty()) {
+            let (key, value) = condition.split_once('=')
+                .ok_or_else(|| format!("expected key=value, found {:?}", condition))?;
+            match key.trim() {
+                "room" => room = Some(Pattern::new(value).map_err(|e| format!("invalid glob {:?}: {}", value, e))?),
+                "max-age" => max_age = Some(parse_duration(value)?),
+                "max-count" => max_count = Some(value.parse().map_err(|_| format!("invalid count {:?}", value))?),
+                key => return Err(format!("unknown condition {:?}, expected room, max-age or max-count", key)),
+            }
+        }
+
+        let room = room.ok_or("a rule needs a room, or room=* for every room")?;
+        if max_age.is_none() && max_count.is_none() {
+            return Err("a rule needs a max-age or max-count".to_string());
+        }
+        Ok(Self { room, max_age, max_count })
+    }
+}
+
+/// Parses a duration such as `90s`, `30m`, `12h` or `7d` into seconds.
+pub fn parse_duration(duration: &str) -> Result<u64, String> {
+    let duration = duration.trim();
+    let split = duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let multiplier = match unit.trim().to_lowercase().as_str() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        unit => return Err(format!("unknown duration unit {:?}, expected s, m, h, d or w", unit)),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration {:?}", duration))?;
+    number.checked_mul(multiplier).ok_or_else(|| format!("duration {:?} is too long", duration))
+}
+
+/// Log of the messages sent in every room and direct conversation, kept in a file so they survive restarts.
+///
+/// Messages are appended to the file as CBOR records as they are sent or received. Older messages
+/// are dropped by the retention rules when the log is loaded and every `RETENTION_INTERVAL` while
+/// running, and the file is rewritten without them.
+#[derive(Debug, Default)]
+pub struct ChatLog {
+    /// File messages are appended to, or `None` to keep them in memory only
+    pub path: Option<PathBuf>,
+    /// Rules deciding how long messages are kept, the first matching a room applying to it
+    pub retention: Vec<RetentionRule>,
+}
+
+impl ChatLog {
+    /// Loads the messages kept by the retention rules from a file, by room or conversation.
+    ///
+    /// A record cut short, such as by the app being killed while writing it, ends the log there.
+    pub fn load(path: &Path, retention: Vec<RetentionRule>) -> io::Result<(Self, HashMap<String, Vec<ChatMessage>>)> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut messages: HashMap<String, Vec<ChatMessage>> = HashMap::new();
+        let (mut count, mut cut_short) = (0, false);
+        for logged in serde_cbor::Deserializer::from_slice(&bytes).into_iter::<LoggedMessage>() {
+            match logged {
+                Ok(logged) => {
+                    messages.entry(logged.topic).or_default().push(logged.message);
+                    count += 1;
+                }
+                Err(e) => {
+                    logger::error!("Ignoring the rest of chat history {:?}: {}", path, e);
+                    cut_short = true;
+                    break;
+                }
+            }
+        }
+
+        // Messages synced from other peers are appended after newer ones, so put them back in order
+        for messages in messages.values_mut() {
+            messages.sort_by_key(|message| message.timestamp);
+        }
+        let log = Self { path: Some(path.to_path_buf()), retention };
+        log.apply_retention(&mut messages, history::unix_now());
+        messages.retain(|_, messages| !messages.is_empty());
+
+        // Rewrite the file without dropped messages, and without a broken record which would hide
+        // any messages appended after it
+        let kept: usize = messages.values().map(Vec::len).sum();
+        if kept < count || cut_short {
+            log.rewrite(&messages)?;
+        }
+        Ok((log, messages))
+    }
+
+    /// Drops the messages of rooms and conversations which are past their retention, and rewrites
+    /// the log file without them, so messages do not build up while the app keeps running.
+    pub fn prune(&self, public_messages: &mut HashMap<String, Vec<ChatMessage>>, private_messages: &mut HashMap<String, Vec<ChatMessage>>) {
+        let now = history::unix_now();
+        let dropped = self.apply_retention(public_messages, now) + self.apply_retention(private_messages, now);
+        if dropped == 0 {
+            return;
+        }
+        logger::info!("Dropped {} messages past their retention", dropped);
+        if let Err(e) = self.rewrite(public_messages.iter().chain(private_messages.iter())) {
+            logger::error!("Error rewriting chat history {:?}: {:?}", self.path, e);
+        }
+    }
+
+    // Keeps the messages of each room allowed by the first rule matching it, returning how many were dropped
+    fn apply_retention(&self, messages: &mut HashMap<String, Vec<ChatMessage>>, now: u64) -> usize {
+        let mut dropped = 0;
+        for (topic, messages) in messages.iter_mut() {
+            if let Some(rule) = self.retention.iter().find(|rule| rule.room.matches(topic)) {
+                let before = messages.len();
+                rule.apply(messages, now);
+                dropped += before - messages.len();
+            }
+        }
+        dropped
+    }
+
+    /// Records a message sent to a room or conversation, appending it to the log file.
+    pub fn record(&self, topic: &str, message: &ChatMessage) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let logged = LoggedMessage { topic: topic.to_string(), message: message.clone() };
+        let written = serde_cbor::to_vec(&logged)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|bytes| OpenOptions::new().create(true).append(true).open(path)?.write_all(&bytes));
+        if let Err(e) = written {
+            logger::error!("Error writing chat history {:?}: {:?}", path, e);
+        }
+    }
+
+    // Replaces the log file with the messages kept, writing them to a temporary file first so the
+    // log is not lost if the app is killed part way through
+    fn rewrite<'a>(&self, messages: impl IntoIterator<Item = (&'a String, &'a Vec<ChatMessage>)>) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut bytes = Vec::new();
+        for (topic, messages) in messages {
+            for message in messages {
+                let logged = LoggedMessage { topic: topic.clone(), message: message.clone() };
+                serde_cbor::to_writer(&mut bytes, &logged).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, bytes)?;
+        std::fs::rename(&temporary, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(30 * 60));
+        assert_eq!(parse_duration(" 2W "), Ok(2 * 7 * 24 * 60 * 60));
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("1y").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+    }
+
+    #[test]
+    fn parses_retention_rules() {
+        let rule: RetentionRule = "room=eng*, max-age=7d, max-count=100".parse().unwrap();
+        assert!(rule.room.matches("engineering"));
+        assert_eq!((rule.max_age, rule.max_count), (Some(7 * 24 * 60 * 60), Some(100)));
+        assert!("max-age=7d".parse::<RetentionRule>().is_err());
+        assert!("room=*".parse::<RetentionRule>().is_err());
+        assert!("room=*,max-size=1".parse::<RetentionRule>().is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::logger;
+use crate::network::transfer::DownloadOutcome;
+
+/// Whether a file was sent to or received from other peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Sent,
+    Received,
+}
+
+/// How a transfer recorded in the history ended.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TransferOutcome {
+    /// Every byte was transferred, and verified against the hash if it was received
+    Completed,
+    /// Only some of the file was sent, for example because the peer also downloaded it from others
+    Partial { bytes: u64 },
+    /// The received file did not match its hash and was discarded
+    Corrupted,
+    Failed(String),
+}
+
+impl From<DownloadOutcome> for TransferOutcome {
+    fn from(outcome: DownloadOutcome) -> Self {
+        match outcome {
+            DownloadOutcome::Verified => TransferOutcome::Completed,
+            DownloadOutcome::Corrupted => TransferOutcome::Corrupted,
+            DownloadOutcome::Failed(reason) => TransferOutcome::Failed(reason),
+        }
+    }
+}
+
+/// A peer taking part in a recorded transfer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPeer {
+    pub peer_id: String,
+    /// Username the peer had at the time of the transfer
+    pub username: Option<String>,
+}
+
+/// A transfer which has finished, as recorded in the history file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub filename: String,
+    pub hash: String,
+    pub size: u64,
+    /// Peers the file was received from, or the peer it was sent to
+    pub peers: Vec<HistoryPeer>,
+    pub direction: TransferDirection,
+    /// Seconds since the Unix epoch when the transfer started
+    pub started: u64,
+    /// Seconds since the Unix epoch when the transfer ended
+    pub finished: u64,
+    pub outcome: TransferOutcome,
+}
+
+impl HistoryEntry {
+    /// Returns true if every search term appears in the file name, hash, or a peer's ID or username,
+    /// ignoring case.
+    pub fn matches(&self, terms: &[String]) -> bool {
+        let mut fields = vec![self.filename.to_lowercase(), self.hash.to_lowercase()];
+        for peer in &self.peers {
+            fields.push(peer.peer_id.to_lowercase());
+            fields.extend(peer.username.as_ref().map(|username| username.to_lowercase()));
+        }
+        terms.iter().all(|term| {
+            let term = term.to_lowercase();
+            fields.iter().any(|field| field.contains(&term))
+        })
+    }
+}
+
+/// Log of every transfer which has finished, kept in a file so it survives restarts.
+///
+/// Entries are appended to the file as CBOR records as they finish, so an entry is never lost
+/// by the app exiting before it saves.
+#[derive(Debug, Default)]
+pub struct History {
+    /// File the history is appended to, or `None` to keep it in memory only
+    pub path: Option<PathBuf>,
+    /// Recorded transfers, oldest first
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    /// Loads the history from a file, starting an empty one if the file does not exist yet.
+    ///
+    /// A record cut short, such as by the app being killed while writing it, ends the history there.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        for entry in serde_cbor::Deserializer::from_slice(&bytes).into_iter::<HistoryEntry>() {
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    logger::error!("Ignoring the rest of transfer history {:?}: {}", path, e);
+                    break;
+                }
+            }
+        }
+        Ok(Self { path: Some(path.to_path_buf()), entries })
+    }
+
+    /// Records a finished transfer, appending it to the history file.
+    pub fn record(&mut self, entry: HistoryEntry) {
+        if let Some(path) = &self.path {
+            let written = serde_cbor::to_vec(&entry)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                .and_then(|bytes| OpenOptions::new().create(true).append(true).open(path)?.write_all(&bytes));
+            if let Err(e) = written {
+                logger::error!("Error writing transfer history {:?}: {:?}", path, e);
+            }
+        }
+        self.entries.push(entry);
+    }
+
+    /// Returns the entries matching every search term, newest first.
+    pub fn search(&self, terms: &[String]) -> Vec<&HistoryEntry> {
+        self.entries.iter().rev().filter(|entry| entry.matches(terms)).collect()
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
@@ -0,0 +1,44 @@
+use libp2p::identity::Keypair;
+use std::io::{self, Write};
+use std::path::Path;
+use crate::logger;
+
+/// Loads the keypair this peer is identified by, creating and saving a new one on the first run.
+///
+/// Keeping the keypair keeps the peer ID the same across restarts, so direct conversations, queued
+/// and parked messages, swap ratios and `peer=` rules still refer to this peer after a restart.
+pub fn load_or_create(path: &Path) -> io::Result<Keypair> {
+    match std::fs::read(path) {
+        Ok(bytes) => {
+            return Keypair::from_protobuf_encoding(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    let bytes = keypair.to_protobuf_encoding().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // The secret key is only readable by its owner
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(&bytes)?;
+    logger::info!("Created a new identity {} in {:?}", keypair.public().to_peer_id(), path);
+    Ok(keypair)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_same_identity() {
+        let path = std::env::temp_dir().join(format!("swapbytes-identity-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let created = load_or_create(&path).unwrap();
+        let loaded = load_or_create(&path).unwrap();
+        assert_eq!(created.public().to_peer_id(), loaded.public().to_peer_id());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::logger;
+
+/// How often the ledger is written to its file while bytes are being swapped.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Bytes swapped with a single peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    /// Bytes of chunks sent to the peer
+    pub uploaded: u64,
+    /// Bytes of verified chunks received from the peer
+    pub downloaded: u64,
+}
+
+impl Balance {
+    /// Returns how much the peer has given compared to how much it has taken, or `None` if it has not
+    /// taken anything yet.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.uploaded > 0).then(|| self.downloaded as f64 / self.uploaded as f64)
+    }
+}
+
+/// Tracks the bytes swapped with each peer, kept in a file so ratios carry over between sessions.
+///
+/// Peers are identified by their peer ID, which stays the same across restarts as long as they keep
+/// their identity file.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    /// File the ledger is saved to, or `None` to keep it in memory only
+    pub path: Option<PathBuf>,
+    balances: HashMap<PeerId, Balance>,
+    /// Set when bytes have been recorded since the ledger was last saved
+    changed: bool,
+}
+
+impl Ledger {
+    /// Loads the ledger from a file, starting an empty one if the file does not exist yet.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let balances: HashMap<String, Balance> = match std::fs::read(path) {
+            Ok(bytes) => serde_cbor::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let balances = balances.into_iter()
+            .filter_map(|(peer, balance)| Some((peer.parse().ok()?, balance)))
+            .collect();
+        Ok(Self { path: Some(path.to_path_buf()), balances, changed: false })
+    }
+
+    /// Writes the ledger to its file if anything has been recorded since it was last saved, through
+    /// a temporary file so the ledger is not lost if the app is killed part way through.
+    pub fn save(&mut self) {
+        let Some(path) = self.path.as_ref().filter(|_| self.changed) else {
+            return;
+        };
+        let balances: HashMap<String, Balance> = self.balances.iter()
+            .map(|(peer, balance)| (peer.to_string(), *balance))
+            .collect();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let written = serde_cbor::to_vec(&balances)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|bytes| std::fs::write(&temporary, bytes))
+            .and_then(|_| std::fs::rename(&temporary, path));
+        match written {
+            Ok(()) => self.changed = false,
+            Err(e) => logger::error!("Error writing ledger {:?}: {:?}", path, e),
+        }
+    }
+
+    /// Returns the bytes swapped with a peer so far.
+    pub fn balance(&self, peer: &PeerId) -> Balance {
+        self.balances.get(peer).copied().unwrap_or_default()
+    }
+
+    /// Records bytes sent to a peer.
+    pub fn record_upload(&mut self, peer: PeerId, bytes: u64) {
+        self.balances.entry(peer).or_default().uploaded += bytes;
+        self.changed = true;
+    }
+
+    /// Records bytes received from a peer.
+    pub fn record_download(&mut self, peer: PeerId, bytes: u64) {
+        self.balances.entry(peer).or_default().downloaded += bytes;
+        self.changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_balances_across_sessions() {
+        let path = std::env::temp_dir().join(format!("swapbytes-ledger-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (first, second) = (PeerId::random(), PeerId::random());
+
+        let mut ledger = Ledger::load(&path).unwrap();
+        ledger.record_upload(first, 100);
+        ledger.record_download(first, 40);
+        ledger.record_download(second, 7);
+        ledger.save();
+
+        let ledger = Ledger::load(&path).unwrap();
+        assert_eq!(ledger.balance(&first), Balance { uploaded: 100, downloaded: 40 });
+        assert_eq!(ledger.balance(&second), Balance { uploaded: 0, downloaded: 7 });
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
-use std::{error::Error, io};
+use std::{error::Error, io, path::PathBuf};
+use clap::Parser;
+use libp2p::identity::Keypair;
 use ratatui::{
     crossterm::{
         event::{DisableMouseCapture, EnableMouseCapture},
@@ -14,6 +16,14 @@ use state::APP;
 use state::Screen;
 
 pub mod logger;
+pub mod share;
+pub mod policy;
+pub mod history;
+pub mod ledger;
+pub mod preview;
+pub mod chat;
+pub mod chat_log;
+pub mod identity;
 
 pub mod ui {
     pub mod screens {
@@ -21,8 +31,11 @@ pub mod ui {
         pub mod login_screen;
         pub mod select_room_screen;
         pub mod dm_screen;
+        pub mod transfers_screen;
+        pub mod history_screen;
     }
     pub mod ui_router;
+    pub mod chat_view;
 }
 pub mod network {
     pub mod network_behaviour {
@@ -30,13 +43,128 @@ pub mod network {
         pub mod gossipsub_behaviour;
         pub mod kademlia_behaviour;
         pub mod request_response_behaviour;
+        pub mod file_transfer_behaviour;
+        pub mod chat_sync_behaviour;
     }
+    pub mod transfer;
+    pub mod bundle;
+    pub mod bandwidth;
+    pub mod compression;
+    pub mod outbox;
+    #[allow(clippy::module_inception)]
     pub mod network;
 }
 
 use ui::screens::dm_screen::DmScreen;
 use ui::ui_router::render;
 use network::network::Client;
+use policy::{AcceptRule, RatioAction, RatioPolicy};
+use chat_log::RetentionRule;
+use network::bandwidth::{BandwidthLimits, SCHEDULER};
+
+// A rate limit as parsed from the command line, where `None` is `off`. Named so clap takes the
+// parser's `Option` as the value rather than as the flag being optional
+type RateLimit = Option<u64>;
+
+/// Command line options for SwapBytes.
+#[derive(Parser, Debug)]
+#[command(about = "Peer to peer chat and file sharing")]
+struct Opt {
+    /// Directory whose files other peers can request
+    #[arg(long, default_value = "shared")]
+    share_dir: PathBuf,
+
+    /// Directory received files are saved to
+    #[arg(long, default_value = "downloads")]
+    download_dir: PathBuf,
+
+    /// File every finished transfer is recorded in
+    #[arg(long, default_value = "transfer-history.cbor")]
+    history_file: PathBuf,
+
+    /// File every chat message is recorded in
+    #[arg(long, default_value = "chat-history.cbor")]
+    chat_history_file: PathBuf,
+
+    /// File the key this peer is identified by is kept in, so its peer ID stays the same across restarts
+    #[arg(long, default_value = "identity.key")]
+    identity_file: PathBuf,
+
+    /// File the bytes swapped with each peer are kept in, so swap ratios carry over between sessions
+    #[arg(long, default_value = "ledger.cbor")]
+    ledger_file: PathBuf,
+
+    /// File direct messages waiting to be delivered are kept in
+    #[arg(long, default_value = "outbox.cbor")]
+    outbox_file: PathBuf,
+
+    /// Keep the messages of matching rooms only for a while, such as `room=engineering,max-age=7d,max-count=1000`.
+    /// Direct conversations can be matched with `room=*_*`. Can be given more than once, the first
+    /// matching rule applying to each room. Messages are kept forever otherwise
+    #[arg(long = "retain", value_name = "RULE")]
+    retain: Vec<RetentionRule>,
+
+    /// Store direct messages to peers who are away in the DHT, encrypted for them, so they can be
+    /// picked up even if this peer has gone by the time they return. The records are signed so
+    /// other peers cannot forge them, but any peer can still overwrite them, so delivery is not
+    /// guaranteed
+    #[arg(long)]
+    park_messages: bool,
+
+    /// Most messages asked for from other members when joining a room
+    #[arg(long, value_name = "COUNT", default_value_t = 100)]
+    sync_limit: usize,
+
+    /// Oldest messages asked for from other members when joining a room, such as `12h` or `7d`
+    #[arg(long, value_name = "AGE", default_value = "1d", value_parser = chat_log::parse_duration)]
+    sync_age: u64,
+
+    /// Accept file requests matching a rule without asking, such as `peer=<peer id>,path=builds/**,max-size=100MB`.
+    /// Can be given more than once.
+    #[arg(long = "auto-accept", value_name = "RULE")]
+    auto_accept: Vec<AcceptRule>,
+
+    /// Largest file or bundle sent to peers, such as `2GB`. Larger requests are refused
+    #[arg(long, value_name = "SIZE", value_parser = policy::parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Keep the partial files of downloads cancelled by their providers, so they can be resumed
+    #[arg(long)]
+    keep_partials: bool,
+
+    /// Show how long ago chat messages were sent, rather than the time they were sent
+    #[arg(long)]
+    relative_times: bool,
+
+    /// Smallest ratio of bytes received from a peer to bytes sent to it, below which its requests
+    /// are deprioritised or refused
+    #[arg(long, value_name = "RATIO")]
+    min_ratio: Option<f64>,
+
+    /// What to do with requests from peers below the minimum ratio
+    #[arg(long, value_enum, default_value_t = RatioAction::Deprioritise)]
+    ratio_action: RatioAction,
+
+    /// Bytes a peer may take before its ratio is checked
+    #[arg(long, value_name = "SIZE", default_value = "16MB", value_parser = policy::parse_size)]
+    ratio_grace: u64,
+
+    /// Total upload rate limit per second, such as `1MB`, or `off`
+    #[arg(long, value_name = "RATE", value_parser = network::bandwidth::parse_rate)]
+    max_upload: Option<RateLimit>,
+
+    /// Total download rate limit per second
+    #[arg(long, value_name = "RATE", value_parser = network::bandwidth::parse_rate)]
+    max_download: Option<RateLimit>,
+
+    /// Upload rate limit per second for each peer
+    #[arg(long, value_name = "RATE", value_parser = network::bandwidth::parse_rate)]
+    peer_max_upload: Option<RateLimit>,
+
+    /// Download rate limit per second for each peer
+    #[arg(long, value_name = "RATE", value_parser = network::bandwidth::parse_rate)]
+    peer_max_download: Option<RateLimit>,
+}
 
 // Sets up the terminal by enabling raw mode, switching to the alternate screen,
 // and enabling mouse capture.
@@ -65,8 +193,8 @@ fn restore_terminal(
 }
 
 // Initializes the network by creating a client and starting the event loop in a background task.
-async fn init_network() -> Result<Client, Box<dyn Error>> {
-    let (mut network_client, network_event_loop) = network::network::new().await?;
+async fn init_network(keypair: Keypair) -> Result<Client, Box<dyn Error>> {
+    let (mut network_client, network_event_loop) = network::network::new(keypair).await?;
     spawn(network_event_loop.run());
     network_client
         .start_listening("/ip4/0.0.0.0/tcp/0".parse()?)
@@ -100,18 +228,29 @@ async fn run_screen_loop(
     let mut break_loop = false;
 
     while !break_loop {
-        let mut app = APP.lock().unwrap();
-        logger::info!("Curr Screen: {:?}", app.current_room.clone());
-        match app.current_screen.clone() {
-            Screen::DMScreen if app.peers.len() >= 1 => {
-                app.update_usernames(network_client).await;
+        let (current_screen, missing_usernames) = {
+            let mut app = APP.lock().unwrap();
+            logger::info!("Curr Screen: {:?}", app.current_room.clone());
+            let current_screen = app.current_screen.clone();
+            let missing_usernames = if current_screen == Screen::DMScreen && !app.peers.is_empty() {
+                app.update_usernames()
+            } else {
+                Vec::new()
+            };
+            (current_screen, missing_usernames)
+        };
+
+        match current_screen {
+            Screen::DMScreen => {
+                for peer_id in missing_usernames {
+                    network_client.get_username(peer_id).await;
+                }
             }
             Screen::SelectRoomScreen => {
                 network_client.get_rooms().await;
             }
             _ => {}
         }
-        drop(app);
 
         // Draw the current UI and process events
         terminal.draw(|f| render(f, dm_screen))?;
@@ -122,12 +261,67 @@ async fn run_screen_loop(
 }
 
 // Main entry point for the application.
+// Reads the local time zone while it can still be read safely, then starts the runtime.
+fn main() -> Result<(), Box<dyn Error>> {
+    lazy_static::initialize(&ui::chat_view::LOCAL_OFFSET);
+    tokio::runtime::Runtime::new()?.block_on(run())
+}
+
 // Initializes the network, sets up the terminal, and runs the main event loop.
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn run() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::parse();
+
     // Initialize logger and network
     logger::initialize();
-    let mut network_client = init_network().await?;
+    std::fs::create_dir_all(&opt.share_dir)?;
+    std::fs::create_dir_all(&opt.download_dir)?;
+    {
+        let mut app = APP.lock().unwrap();
+        app.share_dir = opt.share_dir;
+        app.accept_policy.rules = opt.auto_accept;
+        app.max_file_size = opt.max_file_size;
+        app.keep_partials = opt.keep_partials;
+        app.relative_times = opt.relative_times;
+        app.sync_limit = opt.sync_limit.min(chat::MAX_SYNC_MESSAGES);
+        app.sync_age = opt.sync_age;
+        app.park_messages = opt.park_messages;
+        app.ratio_policy = RatioPolicy {
+            min_ratio: opt.min_ratio,
+            action: opt.ratio_action,
+            grace: opt.ratio_grace,
+        };
+        app.history = history::History::load(&opt.history_file)?;
+        app.ledger = ledger::Ledger::load(&opt.ledger_file)?;
+
+        // Restore the messages kept from earlier sessions
+        let (chat_log, messages) = chat_log::ChatLog::load(&opt.chat_history_file, opt.retain)?;
+        app.chat_log = chat_log;
+        for (topic, messages) in messages {
+            let conversations = if topic.len() <= 64 { &mut app.public_messages } else { &mut app.private_messages };
+            conversations.insert(topic, messages);
+        }
+
+        // Restore the direct messages still waiting to be delivered, which are sent once their
+        // recipients are found again
+        app.outbox = network::outbox::load(&opt.outbox_file)?;
+        let queued: Vec<String> = app.outbox.values().flatten().map(|message| message.id.clone()).collect();
+        for id in queued {
+            app.delivery.insert(id, chat::DeliveryState::Queued);
+        }
+        app.outbox_file = Some(opt.outbox_file);
+
+        // Restore partially received files so they can be resumed
+        app.downloads = network::transfer::load_partial_downloads(&opt.download_dir);
+        app.download_dir = opt.download_dir;
+    }
+    SCHEDULER.lock().unwrap().limits = BandwidthLimits {
+        upload: opt.max_upload.flatten(),
+        download: opt.max_download.flatten(),
+        peer_upload: opt.peer_max_upload.flatten(),
+        peer_download: opt.peer_max_download.flatten(),
+    };
+    let keypair = identity::load_or_create(&opt.identity_file)?;
+    let mut network_client = init_network(keypair).await?;
 
     // Setup terminal
     let mut terminal = setup_terminal()?;
@@ -141,6 +335,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Run the event loop for the login screen and main application
     run_screen_loop(&mut terminal, &mut network_client, &mut dm_screen).await?;
+    {
+        let mut app = APP.lock().unwrap();
+        app.log_unfinished_uploads();
+        app.ledger.save();
+    }
 
     // Restore terminal state after program exits
     restore_terminal(&mut terminal)?;
@@ -0,0 +1,398 @@
+use lazy_static::lazy_static;
+use libp2p::PeerId;
+use libp2p_request_response::ResponseChannel;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::logger;
+use crate::network::network::CHUNK_TIMEOUT;
+use crate::network::transfer::{ChunkRequest, ChunkResponse, CHUNK_SIZE, MAX_IN_FLIGHT};
+use crate::policy;
+
+/// Interval bandwidth is handed out to rate limited transfers at.
+pub const SCHEDULE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long after a chunk is sent to a peer its transfer still counts as active, keeping
+/// deprioritised peers waiting.
+const ACTIVE_UPLOAD_WINDOW: Duration = Duration::from_secs(1);
+
+/// Most chunk requests queued for a single transfer, well above what a peer keeps in flight.
+pub const MAX_QUEUED_CHUNKS: usize = 4 * MAX_IN_FLIGHT;
+
+/// Direction of a transfer, which is limited separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Transfer rate limits in bytes per second, where `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BandwidthLimits {
+    /// Total rate chunks are sent to every peer at
+    pub upload: Option<u64>,
+    /// Total rate chunks are received from every peer at
+    pub download: Option<u64>,
+    /// Rate chunks are sent to each peer at
+    pub peer_upload: Option<u64>,
+    /// Rate chunks are received from each peer at
+    pub peer_download: Option<u64>,
+}
+
+impl BandwidthLimits {
+    /// Returns the global and per-peer limits for a direction.
+    pub fn get(&self, direction: Direction) -> (Option<u64>, Option<u64>) {
+        match direction {
+            Direction::Upload => (self.upload, self.peer_upload),
+            Direction::Download => (self.download, self.peer_download),
+        }
+    }
+
+    /// Sets one of the limits by name, as used by the `!limit` command.
+    pub fn set(&mut self, name: &str, rate: Option<u64>) -> Result<(), String> {
+        match name {
+            "upload" => self.upload = rate,
+            "download" => self.download = rate,
+            "peer-upload" => self.peer_upload = rate,
+            "peer-download" => self.peer_download = rate,
+            name => return Err(format!("unknown limit {:?}, expected upload, download, peer-upload or peer-download", name)),
+        }
+        Ok(())
+    }
+}
+
+/// Parses a rate such as `512KB` in bytes per second, or `off` for no limit.
+///
+/// A rate of zero is refused, as nothing could ever be sent or received at it.
+pub fn parse_rate(rate: &str) -> Result<Option<u64>, String> {
+    match rate.trim().trim_end_matches("/s") {
+        "off" | "none" => Ok(None),
+        rate => match policy::parse_size(rate)? {
+            0 => Err(format!("a rate of {:?} would stop every transfer, use `off` to remove the limit", rate)),
+            rate => Ok(Some(rate)),
+        },
+    }
+}
+
+// Bytes which may be transferred, earned at the rate of a limit
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        Self { tokens: CHUNK_SIZE as f64, updated: Instant::now() }
+    }
+
+    // Adds the bytes earned since the last update, saving up at most a second's worth or a whole
+    // chunk, whichever is larger, so limits below the chunk size still make progress
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let capacity = rate.max(CHUNK_SIZE) as f64;
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate as f64).min(capacity);
+        self.updated = now;
+    }
+}
+
+/// A chunk request waiting for upload bandwidth, with the peer it came from.
+pub type QueuedChunk<C = ResponseChannel<ChunkResponse>> = (PeerId, ChunkRequest, C);
+
+// Chunk requests for one transfer, in the order they arrived, with the length of each chunk and
+// when it was queued
+type ChunkQueue<C> = VecDeque<(ChunkRequest, C, u64, Instant)>;
+
+/// Shares the upload and download bandwidth between transfers, within the configured limits.
+///
+/// Each limit is enforced with a token bucket, and a chunk is only sent or requested once every
+/// limit that applies to it has the chunk's length of bandwidth available. While uploads are limited,
+/// chunk requests are queued per transfer and served in turn, so each transfer gets an equal share
+/// however many chunks its peer asks for at once. Requests the peer has stopped waiting for are
+/// dropped rather than served. Chunk requests from deprioritised peers are also queued while other
+/// peers are downloading, whether or not uploads are limited.
+pub struct Scheduler<C = ResponseChannel<ChunkResponse>> {
+    pub limits: BandwidthLimits,
+    /// Token buckets by direction, for every peer together (`None`) or a single peer
+    buckets: HashMap<(Direction, Option<PeerId>), TokenBucket>,
+    /// Chunk requests waiting to be served, by the peer and hash of the file they are for
+    queued: HashMap<(PeerId, String), ChunkQueue<C>>,
+    /// Order queued transfers are served in, each going to the back after it is served a chunk
+    turns: VecDeque<(PeerId, String)>,
+    /// Peers whose chunks are only served when nobody else is waiting
+    deprioritised: HashSet<PeerId>,
+    /// When a chunk was last sent to each peer which is not deprioritised
+    served: HashMap<PeerId, Instant>,
+    /// Number of times downloads have been scheduled, used to rotate which goes first
+    pub download_turn: usize,
+}
+
+impl<C> Default for Scheduler<C> {
+    fn default() -> Self {
+        Self {
+            limits: BandwidthLimits::default(),
+            buckets: HashMap::new(),
+            queued: HashMap::new(),
+            turns: VecDeque::new(),
+            deprioritised: HashSet::new(),
+            served: HashMap::new(),
+            download_turn: 0,
+        }
+    }
+}
+
+impl<C> Scheduler<C> {
+    /// Returns true if any limit applies to a direction.
+    pub fn is_limited(&self, direction: Direction) -> bool {
+        let (global, per_peer) = self.limits.get(direction);
+        global.is_some() || per_peer.is_some()
+    }
+
+    /// Takes the bandwidth for a chunk sent to or received from a peer, if every limit allows it.
+    pub fn try_take(&mut self, direction: Direction, peer: PeerId, bytes: u64) -> bool {
+        let (global, per_peer) = self.limits.get(direction);
+        let limits = [(None, global), (Some(peer), per_peer)];
+
+        for (key, rate) in limits {
+            if let Some(rate) = rate {
+                let bucket = self.buckets.entry((direction, key)).or_insert_with(TokenBucket::new);
+                bucket.refill(rate);
+                if bucket.tokens < bytes as f64 {
+                    return false;
+                }
+            }
+        }
+        for (key, rate) in limits {
+            if rate.is_some() {
+                if let Some(bucket) = self.buckets.get_mut(&(direction, key)) {
+                    bucket.tokens -= bytes as f64;
+                }
+            }
+        }
+        true
+    }
+
+    /// Records a chunk sent to a peer which is not deprioritised, so deprioritised peers wait for
+    /// its transfer.
+    pub fn record_served(&mut self, peer: PeerId) {
+        self.deprioritised.remove(&peer);
+        let now = Instant::now();
+        self.served.retain(|_, served| now.duration_since(*served) < ACTIVE_UPLOAD_WINDOW);
+        self.served.insert(peer, now);
+    }
+
+    /// Returns true if a peer other than `peer` which is not deprioritised is waiting for chunks,
+    /// or was sent one recently.
+    pub fn others_active(&self, peer: PeerId) -> bool {
+        let waiting = self.turns.iter().any(|(other, _)| *other != peer && !self.deprioritised.contains(other));
+        waiting || self.served.iter().any(|(other, served)| *other != peer && served.elapsed() < ACTIVE_UPLOAD_WINDOW)
+    }
+
+    /// Queues a chunk request of `len` bytes until there is upload bandwidth to serve it, behind
+    /// every other peer's if the peer is deprioritised.
+    ///
+    /// At most `MAX_QUEUED_CHUNKS` requests are kept for each transfer, dropping the oldest first.
+    pub fn queue_chunk(&mut self, peer: PeerId, request: ChunkRequest, channel: C, len: u64, deprioritised: bool) {
+        if deprioritised {
+            self.deprioritised.insert(peer);
+        } else {
+            self.deprioritised.remove(&peer);
+        }
+        let key = (peer, request.file_id.clone());
+        if !self.queued.contains_key(&key) {
+            self.turns.push_back(key.clone());
+        }
+        let queue = self.queued.entry(key).or_default();
+        queue.push_back((request, channel, len, Instant::now()));
+        if queue.len() > MAX_QUEUED_CHUNKS {
+            if let Some((request, _, _, _)) = queue.pop_front() {
+                logger::info!("Dropping chunk {} of {} for {}, too many chunks queued", request.index, request.file_id, peer);
+            }
+        }
+    }
+
+    /// Removes every queued chunk request for a file sent to a peer, so a cancelled upload stops
+    /// taking its turns. Returns the removed requests so they can still be answered.
+    pub fn cancel(&mut self, peer: PeerId, file_id: &str) -> Vec<QueuedChunk<C>> {
+        let key = (peer, file_id.to_string());
+        self.turns.retain(|turn| *turn != key);
+        self.queued.remove(&key)
+            .map(|queue| queue.into_iter().map(|(request, channel, _, _)| (peer, request, channel)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of chunk requests waiting for upload bandwidth.
+    pub fn queued_chunks(&self) -> usize {
+        self.queued.values().map(VecDeque::len).sum()
+    }
+
+    /// Takes the queued chunk requests which can be served now, one from each transfer in turn.
+    ///
+    /// Requests queued for longer than `CHUNK_TIMEOUT` are dropped first, as their peer has given
+    /// up on them and asked again.
+    pub fn next_chunks(&mut self) -> Vec<QueuedChunk<C>> {
+        self.drop_expired();
+        let mut ready = Vec::new();
+
+        // Stop once every waiting transfer has been passed over in a row
+        let mut blocked = 0;
+        while blocked < self.turns.len() {
+            let Some(key) = self.turns.pop_front() else {
+                break;
+            };
+            let deprioritised = self.deprioritised.contains(&key.0);
+            if deprioritised && self.others_active(key.0) {
+                self.turns.push_back(key);
+                blocked += 1;
+                continue;
+            }
+            let len = self.queued.get(&key).and_then(VecDeque::front).map_or(CHUNK_SIZE, |(_, _, len, _)| *len);
+            if !self.try_take(Direction::Upload, key.0, len) {
+                self.turns.push_back(key);
+                blocked += 1;
+                continue;
+            }
+            blocked = 0;
+            if !deprioritised {
+                self.record_served(key.0);
+            }
+
+            let Some(queue) = self.queued.get_mut(&key) else {
+                continue;
+            };
+            if let Some((request, channel, _, _)) = queue.pop_front() {
+                ready.push((key.0, request, channel));
+            }
+            if queue.is_empty() {
+                self.queued.remove(&key);
+            } else {
+                self.turns.push_back(key);
+            }
+        }
+        ready
+    }
+
+    // Drops chunk requests which have waited longer than the peer waits for them, along with the
+    // turns of transfers left with nothing queued
+    fn drop_expired(&mut self) {
+        let mut dropped = 0;
+        self.queued.retain(|_, queue| {
+            let waiting = queue.len();
+            queue.retain(|(_, _, _, queued)| queued.elapsed() < CHUNK_TIMEOUT);
+            dropped += waiting - queue.len();
+            !queue.is_empty()
+        });
+        if dropped > 0 {
+            logger::info!("Dropped {} chunk requests which waited too long for bandwidth", dropped);
+            let queued = &self.queued;
+            self.turns.retain(|turn| queued.contains_key(turn));
+        }
+    }
+}
+
+lazy_static! {
+    // Kept separately from the application state, as downloads check it while borrowed from it
+    pub static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("512KB"), Ok(Some(512 * 1024)));
+        assert_eq!(parse_rate("1MB/s"), Ok(Some(1024 * 1024)));
+        assert_eq!(parse_rate("off"), Ok(None));
+        assert_eq!(parse_rate("none"), Ok(None));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("0KB/s").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn charges_the_bytes_taken() {
+        // At a byte per second nothing is earned back while the test runs
+        let mut scheduler = Scheduler::<u64> { limits: BandwidthLimits { upload: Some(1), ..Default::default() }, ..Default::default() };
+        let peer = PeerId::random();
+        assert!(scheduler.try_take(Direction::Upload, peer, CHUNK_SIZE - 100));
+        assert!(scheduler.try_take(Direction::Upload, peer, 100));
+        assert!(!scheduler.try_take(Direction::Upload, peer, 1));
+        assert!(scheduler.try_take(Direction::Download, peer, CHUNK_SIZE));
+    }
+
+    #[test]
+    fn limits_each_peer_separately() {
+        let mut scheduler = Scheduler::<u64> { limits: BandwidthLimits { peer_download: Some(1), ..Default::default() }, ..Default::default() };
+        let (first, second) = (PeerId::random(), PeerId::random());
+        assert!(scheduler.try_take(Direction::Download, first, CHUNK_SIZE));
+        assert!(!scheduler.try_take(Direction::Download, first, CHUNK_SIZE));
+        assert!(scheduler.try_take(Direction::Download, second, CHUNK_SIZE));
+    }
+
+    // Queues chunks of a file, using the chunk index as the response channel
+    fn queue(scheduler: &mut Scheduler<u64>, peer: PeerId, file_id: &str, chunks: u64, deprioritised: bool) {
+        for index in 0..chunks {
+            let request = ChunkRequest { file_id: file_id.to_string(), index };
+            scheduler.queue_chunk(peer, request, index, CHUNK_SIZE, deprioritised);
+        }
+    }
+
+    #[test]
+    fn serves_each_transfer_in_turn() {
+        let mut scheduler = Scheduler::<u64>::default();
+        let (first, second, slow) = (PeerId::random(), PeerId::random(), PeerId::random());
+        queue(&mut scheduler, slow, "c", 2, true);
+        queue(&mut scheduler, first, "a", 3, false);
+        queue(&mut scheduler, second, "b", 1, false);
+        let served: Vec<(PeerId, u64)> = scheduler.next_chunks().into_iter().map(|(peer, _, index)| (peer, index)).collect();
+        assert_eq!(served, [(first, 0), (second, 0), (first, 1), (first, 2)]);
+
+        // The deprioritised peer is served once the others have gone quiet
+        assert!(scheduler.others_active(slow));
+        let quiet = Instant::now().checked_sub(ACTIVE_UPLOAD_WINDOW).unwrap();
+        scheduler.served.values_mut().for_each(|served| *served = quiet);
+        let served: Vec<(PeerId, u64)> = scheduler.next_chunks().into_iter().map(|(peer, _, index)| (peer, index)).collect();
+        assert_eq!(served, [(slow, 0), (slow, 1)]);
+        assert_eq!(scheduler.queued_chunks(), 0);
+    }
+
+    #[test]
+    fn cancels_the_chunks_of_one_transfer() {
+        let mut scheduler = Scheduler::<u64>::default();
+        let peer = PeerId::random();
+        queue(&mut scheduler, peer, "a", 2, false);
+        queue(&mut scheduler, peer, "b", 1, false);
+        let cancelled: Vec<String> = scheduler.cancel(peer, "a").into_iter().map(|(_, request, _)| request.file_id).collect();
+        assert_eq!(cancelled, ["a", "a"]);
+        let served: Vec<String> = scheduler.next_chunks().into_iter().map(|(_, request, _)| request.file_id).collect();
+        assert_eq!(served, ["b"]);
+        assert!(scheduler.cancel(peer, "a").is_empty());
+    }
+
+    #[test]
+    fn drops_chunks_the_peer_stopped_waiting_for() {
+        let mut scheduler = Scheduler::<u64>::default();
+        let peer = PeerId::random();
+        queue(&mut scheduler, peer, "a", 2, false);
+        queue(&mut scheduler, peer, "b", 1, false);
+        let expired = Instant::now().checked_sub(CHUNK_TIMEOUT).unwrap();
+        for (_, _, _, queued) in scheduler.queued.values_mut().flatten() {
+            *queued = expired;
+        }
+        queue(&mut scheduler, peer, "a", 1, false);
+        let served: Vec<(String, u64)> = scheduler.next_chunks().into_iter().map(|(_, request, index)| (request.file_id, index)).collect();
+        assert_eq!(served, [("a".to_string(), 0)]);
+        assert!(scheduler.turns.is_empty());
+    }
+
+    #[test]
+    fn limits_the_chunks_queued_for_a_transfer() {
+        let mut scheduler = Scheduler::<u64>::default();
+        let peer = PeerId::random();
+        queue(&mut scheduler, peer, "a", MAX_QUEUED_CHUNKS as u64 + 1, false);
+        assert_eq!(scheduler.queued_chunks(), MAX_QUEUED_CHUNKS);
+        let first = scheduler.next_chunks().into_iter().next().map(|(_, _, index)| index);
+        assert_eq!(first, Some(1));
+    }
+}
@@ -0,0 +1,171 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use crate::network::transfer::{self, Download, DownloadOutcome, FileDigest};
+
+/// A file in a bundle, at its path relative to the bundle's root.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path using `/` separators, starting with the name of the requested file or directory
+    pub path: String,
+    pub digest: FileDigest,
+}
+
+/// Lists every file of a directory or multi-file transfer, with a hash over the whole list which
+/// identifies the bundle.
+///
+/// The hash comes from the same peer as the entries, so it says nothing about their integrity; each
+/// file is checked against its own digest instead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Name shown for the bundle
+    pub name: String,
+    /// Paths the bundle was requested with, so it can be requested again
+    pub requested: Vec<String>,
+    pub entries: Vec<ManifestEntry>,
+    /// SHA-256 hash over the path and hash of every entry, hex encoded
+    pub hash: String,
+}
+
+impl Manifest {
+    /// Creates a manifest for some files, computing its hash.
+    pub fn new(name: String, requested: Vec<String>, entries: Vec<ManifestEntry>) -> Self {
+        let hash = Self::compute_hash(&entries);
+        Self { name, requested, entries, hash }
+    }
+
+    /// Returns the total size of every file in the bundle.
+    pub fn size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.digest.size).sum()
+    }
+
+    // Hashes the path, size and hash of each entry in order
+    fn compute_hash(entries: &[ManifestEntry]) -> String {
+        let mut hasher = Sha256::new();
+        for entry in entries {
+            hasher.update(entry.path.as_bytes());
+            hasher.update([0]);
+            hasher.update(entry.digest.hash.as_bytes());
+            hasher.update(entry.digest.size.to_be_bytes());
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// Tracks a bundle being received as a set of downloads, one for each distinct file it contains.
+pub struct Bundle {
+    pub manifest: Manifest,
+    /// Peer the bundle was requested from
+    pub peer: PeerId,
+    /// Location each entry is saved to, by its path in the manifest
+    pub destinations: HashMap<String, PathBuf>,
+    /// Result of each distinct file which has finished, by hash
+    pub outcomes: HashMap<String, DownloadOutcome>,
+}
+
+impl Bundle {
+    /// Prepares to receive a bundle into a directory, keeping its relative structure.
+    ///
+    /// Each top-level file or directory of the bundle is given a name which doesn't clash with
+    /// anything already in the directory, and every path component is sanitised. Entries whose
+    /// sanitised paths collide are numbered so each is saved to a file of its own.
+    pub fn new(mut manifest: Manifest, peer: PeerId, dir: &Path) -> Self {
+        let mut roots: HashMap<String, PathBuf> = HashMap::new();
+        let mut destinations = HashMap::new();
+        let mut chosen = HashSet::new();
+        for entry in &manifest.entries {
+            let mut components = entry.path.split('/')
+                .filter(|component| !matches!(Path::new(component).components().next(), None | Some(Component::CurDir | Component::ParentDir)))
+                .map(transfer::sanitise_filename);
+            let Some(root) = components.next() else {
+                continue;
+            };
+            let mut destination = roots.entry(root.clone())
+                .or_insert_with(|| transfer::download_path(dir, &root))
+                .clone();
+            destination.extend(components);
+
+            // Different paths can sanitise to the same name, such as `a/b.txt` and `a_b.txt`, and
+            // must not share a partial file
+            let destination = transfer::unique_path(&destination, &chosen);
+            chosen.insert(destination.clone());
+            destinations.insert(entry.path.clone(), destination);
+        }
+
+        // Entries whose paths have nothing left once sanitised cannot be saved anywhere
+        manifest.entries.retain(|entry| destinations.contains_key(&entry.path));
+
+        Self {
+            manifest,
+            peer,
+            destinations,
+            outcomes: HashMap::new(),
+        }
+    }
+
+    /// Returns the entries of the bundle which have a given hash.
+    pub fn entries_with_hash<'a>(&'a self, hash: &'a str) -> impl Iterator<Item = &'a ManifestEntry> + 'a {
+        self.manifest.entries.iter().filter(move |entry| entry.digest.hash == hash)
+    }
+
+    /// Returns true once every distinct file in the bundle has finished.
+    pub fn is_complete(&self) -> bool {
+        self.manifest.entries.iter().all(|entry| self.outcomes.contains_key(&entry.digest.hash))
+    }
+
+    /// Returns the result of the bundle as a whole, verified only if every file was.
+    pub fn outcome(&self) -> DownloadOutcome {
+        let failures: Vec<&DownloadOutcome> = self.outcomes.values()
+            .filter(|outcome| **outcome != DownloadOutcome::Verified)
+            .collect();
+        match failures.first() {
+            None => DownloadOutcome::Verified,
+            Some(DownloadOutcome::Corrupted) => DownloadOutcome::Corrupted,
+            Some(_) => DownloadOutcome::Failed(format!("{} of {} files failed", failures.len(), self.outcomes.len())),
+        }
+    }
+
+    /// Returns the number of bytes received across the whole bundle.
+    pub fn received_bytes(&self, downloads: &HashMap<String, Download>) -> u64 {
+        self.manifest.entries.iter()
+            .map(|entry| match downloads.get(&entry.digest.hash) {
+                _ if self.outcomes.contains_key(&entry.digest.hash) => entry.digest.size,
+                Some(download) => download.received_bytes(),
+                None => 0,
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> ManifestEntry {
+        let digest = FileDigest { hash: format!("hash of {}", path), chunk_hashes: Vec::new(), size: 1 };
+        ManifestEntry { path: path.to_string(), digest }
+    }
+
+    #[test]
+    fn keeps_every_entry_inside_the_download_directory() {
+        let dir = std::env::temp_dir().join(format!("swapbytes-bundle-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let paths = ["../../escape.txt", "/etc/passwd", "docs/notes.txt", "docs/./notes.txt", "..", "a\\..\\..\\b.txt"];
+        let manifest = Manifest::new("hostile".to_string(), Vec::new(), paths.iter().map(|path| entry(path)).collect());
+        let bundle = Bundle::new(manifest, PeerId::random(), &dir);
+
+        let destination = |path: &str| bundle.destinations.get(path).cloned();
+        assert_eq!(destination("../../escape.txt"), Some(dir.join("escape.txt")));
+        assert_eq!(destination("/etc/passwd"), Some(dir.join("etc/passwd")));
+        assert_eq!(destination("docs/notes.txt"), Some(dir.join("docs/notes.txt")));
+        assert_eq!(destination("docs/./notes.txt"), Some(dir.join("docs/notes (1).txt")));
+        assert_eq!(destination(".."), None);
+        assert_eq!(destination("a\\..\\..\\b.txt"), Some(dir.join("b.txt")));
+        assert_eq!(bundle.manifest.entries.len(), 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
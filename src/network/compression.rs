@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use crate::network::transfer::{self, CHUNK_SIZE};
+
+/// Compression applied to the chunks of a file sent over the `/file-transfer/1` protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// Compression this peer can decode, sent with every file request so the provider can choose one.
+pub const SUPPORTED: &[Compression] = &[Compression::Zstd];
+
+/// zstd level used for chunks, favouring speed as chunks are compressed as they are sent.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Extensions of formats which are already compressed, and are sent as they are.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "png", "pptx", "rar",
+    "tbz", "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Smallest saving on a sample chunk for a file to be worth compressing, as a fraction of its size.
+const MIN_SAVING: f64 = 0.1;
+
+/// Chooses the compression to send a file to a peer with, given the compression the peer can decode.
+///
+/// Files with the extension of an already compressed format are skipped, and otherwise the first
+/// chunk is compressed as a sample, so files which barely shrink are sent as they are.
+pub fn choose(accepted: &[Compression], path: &Path) -> Compression {
+    if !accepted.contains(&Compression::Zstd) {
+        return Compression::None;
+    }
+    let extension = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+        return Compression::None;
+    }
+
+    let Ok(sample) = transfer::read_chunk(path, 0) else {
+        return Compression::None;
+    };
+    match zstd::bulk::compress(&sample, ZSTD_LEVEL) {
+        Ok(compressed) if (compressed.len() as f64) < sample.len() as f64 * (1.0 - MIN_SAVING) => Compression::Zstd,
+        _ => Compression::None,
+    }
+}
+
+/// Compresses a chunk, returning it with the compression actually used, as chunks which would not
+/// shrink are sent as they are.
+pub fn compress_chunk(compression: Compression, data: Vec<u8>) -> (Vec<u8>, Compression) {
+    match compression {
+        Compression::None => (data, Compression::None),
+        Compression::Zstd => match zstd::bulk::compress(&data, ZSTD_LEVEL) {
+            Ok(compressed) if compressed.len() < data.len() => (compressed, Compression::Zstd),
+            _ => (data, Compression::None),
+        },
+    }
+}
+
+/// Decompresses a received chunk, refusing chunks which would decompress to more than a chunk.
+pub fn decompress_chunk(compression: Compression, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data),
+        Compression::Zstd => zstd::bulk::decompress(&data, CHUNK_SIZE as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_chunks() {
+        let text = b"swapbytes ".repeat(1000);
+        let (compressed, compression) = compress_chunk(Compression::Zstd, text.clone());
+        assert_eq!(compression, Compression::Zstd);
+        assert!(compressed.len() < text.len());
+        assert_eq!(decompress_chunk(compression, compressed).unwrap(), text);
+
+        // Data which would not shrink is sent as it is
+        let (sent, compression) = compress_chunk(Compression::Zstd, vec![7]);
+        assert_eq!((sent.as_slice(), compression), ([7].as_slice(), Compression::None));
+        assert_eq!(decompress_chunk(compression, sent).unwrap(), [7]);
+    }
+
+    #[test]
+    fn refuses_chunks_larger_than_a_chunk() {
+        let oversized = vec![0; CHUNK_SIZE as usize + 1];
+        let compressed = zstd::bulk::compress(&oversized, ZSTD_LEVEL).unwrap();
+        assert!(decompress_chunk(Compression::Zstd, compressed).is_err());
+
+        let full = vec![0; CHUNK_SIZE as usize];
+        let (compressed, compression) = compress_chunk(Compression::Zstd, full.clone());
+        assert_eq!(decompress_chunk(compression, compressed).unwrap(), full);
+    }
+}
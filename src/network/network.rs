@@ -13,21 +13,37 @@ use libp2p::{
 };
 use libp2p::gossipsub::IdentTopic;
 use libp2p::StreamProtocol;
+use libp2p::identity::Keypair;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::error::Error;
+use std::io;
 use std::time::Duration;
-use crate::network::network_behaviour::{mdns_behaviour, gossipsub_behaviour, kademlia_behaviour, request_response_behaviour};
-use crate::state::APP;
+use crate::network::network_behaviour::{mdns_behaviour, gossipsub_behaviour, kademlia_behaviour, request_response_behaviour, file_transfer_behaviour, chat_sync_behaviour};
+use crate::network::bundle::Manifest;
+use crate::network::compression::{self, Compression};
+use crate::network::bandwidth::{SCHEDULE_INTERVAL, SCHEDULER};
+use crate::network::outbox;
+use crate::network::transfer::{self, ChunkRequest, ChunkResponse, FileDigest};
+use crate::state::{APP, RequestItem, RequestKind};
+use crate::network::network_behaviour::request_response_behaviour::{Finished, FinishedSender};
+use crate::share::{self, Search, SharedFileInfo};
 use crate::logger;
+use crate::preview::{self, FilePreview};
+use crate::chat::{ChatMessage, HistoryRequest, HistoryResponse};
+use crate::chat_log;
+use crate::ledger;
 use libp2p_request_response::ResponseChannel;
 use libp2p::kad::store::RecordStore;
 
 /// Initializes a new network instance and sets up a Swarm with various network behaviours.
 ///
 /// Configures the Swarm with TCP and QUIC transports, encryption, and multiplexing. Sets up Gossipsub for pub/sub messaging,
-/// MDNS for peer discovery, and Kademlia for distributed hash table operations. Subscribes to Gossipsub topics for rooms.
-pub(crate) async fn new() -> Result<(Client, EventLoop), Box<dyn Error>> {
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+/// MDNS for peer discovery, Kademlia for distributed hash table operations, and request-response protocols for
+/// file exchange, chunked transfers and syncing room history. Subscribes to Gossipsub topics for rooms.
+/// The peer is identified by the given keypair, which is kept across restarts.
+pub(crate) async fn new(keypair: Keypair) -> Result<(Client, EventLoop), Box<dyn Error>> {
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -45,9 +61,31 @@ pub(crate) async fn new() -> Result<(Client, EventLoop), Box<dyn Error>> {
                     StreamProtocol::new("/file-exchange/1"),
                     ProtocolSupport::Full,
                 )],
+                // Requests wait for the provider to accept them, so allow them to outlive the provider's expiry
+                request_response::Config::default().with_request_timeout(REQUEST_EXPIRY + Duration::from_secs(30)),
+            );
+
+            // File contents are streamed separately from the file-exchange handshake, one chunk per request
+            let file_transfer = request_response::cbor::Behaviour::new(
+                [(
+                    StreamProtocol::new("/file-transfer/1"),
+                    ProtocolSupport::Full,
+                )],
+                request_response::Config::default().with_request_timeout(CHUNK_TIMEOUT),
+            );
+
+            // Members of a room send its recent messages to peers which join it later
+            let chat_sync = request_response::cbor::Behaviour::new(
+                [(
+                    StreamProtocol::new("/chat-history/1"),
+                    ProtocolSupport::Full,
+                )],
                 request_response::Config::default(),
             );
-            
+
+            // Chat messages are signed with the same key as the peer ID
+            APP.lock().unwrap().keypair = Some(key.clone());
+
             // Build a Gossipsub network behaviour
             let gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
@@ -56,13 +94,14 @@ pub(crate) async fn new() -> Result<(Client, EventLoop), Box<dyn Error>> {
 
             // Build an MDNS behaviour for peer discovery
             let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(Behaviour { kademlia, request_response, gossipsub, mdns })
+            Ok(Behaviour { kademlia, request_response, file_transfer, chat_sync, gossipsub, mdns })
         })?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
     
+    logger::info!("Local peer ID: {}", swarm.local_peer_id());
     let mut app = APP.lock().unwrap();
-    app.my_peer_id = Some(swarm.local_peer_id().clone());
+    app.my_peer_id = Some(*swarm.local_peer_id());
     for room in &app.rooms {
         // Create a Gossipsub topic for each room and subscribe to it
         let topic = gossipsub::IdentTopic::new(room);
@@ -111,15 +150,14 @@ impl Client {
     /// Sends a message to a topic, which will be published to all subscribers.
     pub(crate) async fn submit_message(
         &mut self,
-        message: String,
+        message: ChatMessage,
         topic: IdentTopic,
     ) {
-        logger::info!("Submitting message: {:?}", message.clone());
+        logger::info!("Submitting message: {:?}", message.id);
         self.sender
-            .send(Command::SendMessage { message: message.clone(), topic })
+            .send(Command::SendMessage { message, topic })
             .await
             .expect("Message Sent.");
-        logger::info!("message sent: {:?}", message.clone());
     }
 
     /// Sends a request for a file to a specific peer.
@@ -136,25 +174,116 @@ impl Client {
             .expect("Command receiver not to be dropped.");
     }
 
-    /// Sends a response with file data to a peer.
+    /// Asks a specific peer to provide a file by its content hash.
     ///
-    /// Reads data from a file and sends it to a peer through the specified response channel.
-    pub(crate) async fn send_response(
+    /// Sends a command to request a file the peer is known to share, such as to retry a failed download.
+    pub(crate) async fn request_hash(
         &mut self,
+        hash: String,
         filename: String,
-        filepath: String,
+        peer: PeerId
+    ) {
+        self.sender
+            .send(Command::RequestHash { hash, filename, peer })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Requests the list of files shared by a specific peer.
+    ///
+    /// Sends a command to ask a peer for the contents of its share directory.
+    pub(crate) async fn list_files(
+        &mut self,
+        peer: PeerId
+    ) {
+        self.sender
+            .send(Command::ListFiles { peer })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Requests several files or directories from a specific peer as one transfer.
+    ///
+    /// Sends a command to request a bundle of paths, with directories included recursively.
+    pub(crate) async fn request_bundle(
+        &mut self,
+        paths: Vec<String>,
+        peer: PeerId
+    ) {
+        self.sender
+            .send(Command::RequestBundle { paths, peer })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Offers to send a file to a specific peer.
+    ///
+    /// Sends a command to offer a file from the share directory, which the peer downloads if they accept.
+    pub(crate) async fn offer_file(
+        &mut self,
+        path: String,
+        peer: PeerId
+    ) {
+        self.sender
+            .send(Command::OfferFile { path, peer })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Asks a specific peer for a preview of a file it shares.
+    ///
+    /// Sends a command to request the metadata and first lines or a small image of a file, shown once it arrives.
+    pub(crate) async fn request_preview(
+        &mut self,
+        path: String,
+        peer: PeerId
+    ) {
+        self.sender
+            .send(Command::RequestPreview { path, peer })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Tells a peer that a transfer of a file with it has been cancelled.
+    ///
+    /// Sends a command to drop any chunks of the file still waiting to be sent to the peer, and to
+    /// ask the peer to stop its side of the transfer.
+    pub(crate) async fn cancel_transfer(
+        &mut self,
+        hash: String,
+        peer: PeerId
+    ) {
+        self.sender
+            .send(Command::CancelTransfer { hash, peer })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Offers a requested file or bundle to a peer.
+    ///
+    /// Resolves the request within the share directory, shares the files for chunked transfer and sends their
+    /// metadata to the peer through the request's response channel.
+    pub(crate) async fn send_response(
+        &mut self,
+        request: RequestItem,
+    ) {
+        self.sender
+            .send(Command::RespondFile { request })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Rejects a requested file.
+    ///
+    /// Sends the reason for refusing the request to the peer through the specified response channel.
+    pub(crate) async fn reject_request(
+        &mut self,
+        request: String,
+        reason: String,
         channel: ResponseChannel<Response>
     ) {
-        // Read data from the file
-        match std::fs::read(&filepath) {
-            Ok(data) => data,
-            Err(e) => {
-                logger::error!("Failed to read file {}: {:?}", filepath, e);
-                Vec::new()
-            },
-        };
         self.sender
-            .send(Command::RespondFile { filename, filepath, channel })
+            .send(Command::RejectFile { request, reason, channel })
             .await
             .expect("Command receiver not to be dropped.");
     }
@@ -189,6 +318,21 @@ impl Client {
             .expect("username got.");
     }
 
+    /// Searches the network for shared files.
+    ///
+    /// Sends a command to find the peers sharing files whose names match every term in the query.
+    pub(crate) async fn search(
+        &mut self,
+        query: String,
+    ) {
+        logger::info!("Searching for: {:?}", query.clone());
+
+        self.sender
+            .send(Command::Search { query })
+            .await
+            .expect("Search started.");
+    }
+
     /// Requests the list of available rooms.
     ///
     /// Sends a command to get the current list of rooms from the network.
@@ -217,10 +361,31 @@ impl Client {
     }
 }
 
+/// How often the shared files are rescanned and announced as provider records.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(120);
+
+/// How long an incoming file request waits to be accepted or rejected before it expires.
+pub const REQUEST_EXPIRY: Duration = Duration::from_secs(120);
+
+/// How often unanswered incoming file requests are checked for expiry.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a chunk request waits for its chunk, allowing for time queued behind a rate limit.
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
 /// Main event loop for handling network events and commands.
 pub(crate) struct EventLoop {
     swarm: Swarm<Behaviour>,
     command_receiver: mpsc::Receiver<Command>,
+    provided_keys: HashSet<kad::RecordKey>,
+    /// Sends the share directory's catalogue back from the background task building it
+    catalogue_sender: mpsc::UnboundedSender<io::Result<Vec<SharedFileInfo>>>,
+    catalogue_receiver: mpsc::UnboundedReceiver<io::Result<Vec<SharedFileInfo>>>,
+    /// Set while the share directory is being catalogued, so scans do not pile up
+    cataloguing: bool,
+    /// Sends back responses which were built off the event loop, to be sent by the swarm
+    finished_sender: FinishedSender,
+    finished_receiver: mpsc::UnboundedReceiver<Finished>,
 }
 
 impl EventLoop {
@@ -229,16 +394,38 @@ impl EventLoop {
         swarm: Swarm<Behaviour>,
         command_receiver: mpsc::Receiver<Command>,
     ) -> Self {
+        let (catalogue_sender, catalogue_receiver) = mpsc::unbounded();
+        let (finished_sender, finished_receiver) = mpsc::unbounded();
         Self {
             swarm,
             command_receiver,
+            provided_keys: HashSet::new(),
+            catalogue_sender,
+            catalogue_receiver,
+            cataloguing: false,
+            finished_sender,
+            finished_receiver,
         }
     }
 
     /// Runs the event loop, processing events and commands.
     pub(crate) async fn run(mut self) {
+        let mut publish_interval = tokio::time::interval(PUBLISH_INTERVAL);
+        let mut expiry_interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        let mut schedule_interval = tokio::time::interval(SCHEDULE_INTERVAL);
+        let mut pick_up_interval = tokio::time::interval(outbox::PICK_UP_INTERVAL);
+        let mut retention_interval = tokio::time::interval(chat_log::RETENTION_INTERVAL);
+        let mut ledger_interval = tokio::time::interval(ledger::SAVE_INTERVAL);
         loop {
             tokio::select! {
+                _ = publish_interval.tick() => self.catalogue_shared_files(),
+                Some(files) = self.catalogue_receiver.next() => self.publish_shared_files(files),
+                Some(finished) = self.finished_receiver.next() => request_response_behaviour::send_finished(&mut self.swarm, finished),
+                _ = expiry_interval.tick() => self.expire_requests(),
+                _ = schedule_interval.tick() => self.schedule_transfers(),
+                _ = pick_up_interval.tick() => outbox::pick_up(&mut self.swarm),
+                _ = retention_interval.tick() => APP.lock().unwrap().prune_messages(),
+                _ = ledger_interval.tick() => APP.lock().unwrap().ledger.save(),
                 event = self.swarm.select_next_some() => self.handle_event(event).await,
                 command = self.command_receiver.next() => match command {
                     Some(c) => self.handle_command(c).await,
@@ -248,13 +435,102 @@ impl EventLoop {
         }
     }
 
+    /// Answers incoming file requests which have waited longer than `REQUEST_EXPIRY`, so the
+    /// requester is told they timed out instead of waiting on them.
+    fn expire_requests(&mut self) {
+        let expired: Vec<RequestItem> = {
+            let mut app = APP.lock().unwrap();
+            let (expired, current) = std::mem::take(&mut app.current_requests)
+                .into_iter()
+                .partition(|request| request.received.elapsed() >= REQUEST_EXPIRY);
+            app.current_requests = current;
+            expired
+        };
+
+        for request in expired {
+            logger::info!("Request from {} for {:?} expired", request.peer_id, request.request_string);
+            let response = Response::Expired { filename: request.request_string };
+            if let Err(e) = self.swarm.behaviour_mut().request_response.send_response(request.response_channel, response) {
+                logger::error!("Failed to send response: {:?}", e);
+            }
+        }
+    }
+
+    /// Hands out bandwidth to rate limited transfers, serving queued chunk requests and requesting
+    /// chunks for downloads in turn.
+    fn schedule_transfers(&mut self) {
+        let ready = SCHEDULER.lock().unwrap().next_chunks();
+        for (peer, request, channel) in ready {
+            file_transfer_behaviour::serve_chunk(&mut self.swarm, peer, request, channel);
+        }
+
+        let mut app = APP.lock().unwrap();
+        transfer::schedule_downloads(&mut app.downloads, &mut self.swarm);
+    }
+
+    /// Rescans the share directory in the background, hashing any files which have changed, unless
+    /// a scan is already running.
+    fn catalogue_shared_files(&mut self) {
+        if self.cataloguing {
+            return;
+        }
+        self.cataloguing = true;
+        let share_dir = APP.lock().unwrap().share_dir.clone();
+        let sender = self.catalogue_sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let _ = sender.unbounded_send(share::catalogue(&share_dir));
+        });
+    }
+
+    /// Keeps the catalogue of the share directory to answer peers from, announces provider records
+    /// for the hash and name keywords of newly shared files, and withdraws the records of files which
+    /// are no longer shared.
+    fn publish_shared_files(&mut self, files: io::Result<Vec<SharedFileInfo>>) {
+        self.cataloguing = false;
+        let files = match files {
+            Ok(files) => files,
+            Err(e) => {
+                logger::error!("Failed to list share directory: {}", e);
+                return;
+            }
+        };
+        *share::CATALOGUE.lock().unwrap() = files.clone();
+
+        let mut keys = HashSet::new();
+        for file in &files {
+            keys.insert(kademlia_behaviour::file_key(&file.hash));
+            keys.extend(share::keywords(&file.name).iter().map(|keyword| kademlia_behaviour::keyword_key(keyword)));
+        }
+
+        // Records already provided are republished by Kademlia itself, so only new keys are announced
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        for key in self.provided_keys.difference(&keys) {
+            kademlia.stop_providing(key);
+        }
+        let mut failed = Vec::new();
+        for key in keys.difference(&self.provided_keys) {
+            if let Err(e) = kademlia.start_providing(key.clone()) {
+                logger::error!("Failed to store provider record locally: {:?}", e);
+                failed.push(key.clone());
+            }
+        }
+        let announced = keys.difference(&self.provided_keys).count() - failed.len();
+        logger::info!("Announced {} new provider records for {} shared files", announced, files.len());
+
+        // Keys which could not be provided are tried again on the next scan
+        for key in &failed {
+            keys.remove(key);
+        }
+        self.provided_keys = keys;
+    }
+
     /// Handles a network event.
     ///
     /// Processes different types of events such as Gossipsub messages or Kademlia queries.
     async fn handle_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
         match event {
             SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(event)) => {
-                gossipsub_behaviour::handle_event(event).await;
+                gossipsub_behaviour::handle_event(event, &mut self.swarm).await;
             },
             
              // Handle MDNS events
@@ -266,16 +542,32 @@ impl EventLoop {
                 kademlia_behaviour::handle_event(event, &mut self.swarm).await;
             },
            
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                logger::info!("Connection established with peer: {peer_id}");
+                let mut app = APP.lock().unwrap();
+                transfer::resume_downloads(&mut app.downloads, &peer_id, &mut self.swarm);
+            },
+
+            SwarmEvent::ConnectionClosed { peer_id, num_established, .. } => {
                 logger::info!("Connection closed for peer: {peer_id}");
                 self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
                 let mut app = APP.lock().unwrap();
                 app.connected_peers -= 1;
+
+                // Keep partial downloads from this peer so they can resume when it reappears
+                if num_established == 0 {
+                    transfer::pause_downloads(&mut app.downloads, &peer_id, &mut self.swarm);
+                }
                 
                 // Remove item from a list (https://stackoverflow.com/questions/26243025/how-to-remove-an-element-from-a-vector-given-the-element)
-                if app.peers.iter().any(|x| *x == peer_id) {
+                if app.peers.contains(&peer_id) {
                     let index = app.peers.iter().position(|x| *x == peer_id).unwrap();
                     app.peers.remove(index);
+
+                    // Keep the peer around to send direct messages to while it is away
+                    if !app.away_peers.contains(&peer_id) {
+                        app.away_peers.push(peer_id);
+                    }
                 }
                     
                 if app.peers_no_username.contains(&peer_id) {
@@ -287,12 +579,20 @@ impl EventLoop {
            
 
             SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(event)) => {
-                request_response_behaviour::handle_event(event).await;
+                request_response_behaviour::handle_event(event, &mut self.swarm, &self.finished_sender).await;
+            },
+
+            SwarmEvent::Behaviour(BehaviourEvent::FileTransfer(event)) => {
+                file_transfer_behaviour::handle_event(event, &mut self.swarm).await;
+            },
+
+            SwarmEvent::Behaviour(BehaviourEvent::ChatSync(event)) => {
+                chat_sync_behaviour::handle_event(event, &mut self.swarm).await;
             },
         
             SwarmEvent::NewListenAddr { address, .. } => {
                 logger::info!("New listening address: {address}");
-                let peer_id = self.swarm.local_peer_id().clone();
+                let peer_id = *self.swarm.local_peer_id();
                 self.swarm.behaviour_mut().kademlia.add_address(&peer_id, address);
             },
         
@@ -306,8 +606,7 @@ impl EventLoop {
     async fn handle_command(&mut self, command: Command) {
         match command {
             Command::SendMessage { message, topic } => {
-                let _ = self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), message.clone().as_bytes());
-                logger::info!("{} sent successfully.", message.clone());
+                outbox::send(&mut self.swarm, topic.hash().as_str(), message);
             }
 
             Command::StartListening { addr, sender } => {
@@ -317,34 +616,59 @@ impl EventLoop {
                 };
             }
             Command::RequestFile { request, peer, } => {
-                self
+                let request_id = self
                     .swarm
                     .behaviour_mut()
                     .request_response
-                    .send_request(&peer, Request { request });
+                    .send_request(&peer, Request::File { request: request.clone(), compression: compression::SUPPORTED.to_vec() });
+                APP.lock().unwrap().track_request(request_id, peer, request, RequestKind::File);
             }
-            Command::RespondFile { filename, filepath, channel } => {
-                // Attempt to read data from the file, defaulting to an empty vector if an error occurs.
-                let data = match std::fs::read(&filepath) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        logger::error!("Failed to read file {}: {}", filepath, e);
-                        Vec::new()
-                    },
-                };
-
-                // Log whether the data is empty.
-                logger::info!("Data provided is empty: {:?}", data.is_empty());
-
-                // Attempt to send the response and log any errors that occur.
-                if let Err(e) = self.swarm
+            Command::RequestHash { hash, filename, peer } => {
+                let request_id = self
+                    .swarm
                     .behaviour_mut()
                     .request_response
-                    .send_response(channel, Response { filename, data }) 
-                {
+                    .send_request(&peer, Request::Hash { hash, compression: compression::SUPPORTED.to_vec() });
+                APP.lock().unwrap().track_request(request_id, peer, filename, RequestKind::File);
+            }
+            Command::RequestBundle { paths, peer } => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer, Request::Bundle { paths: paths.clone(), compression: compression::SUPPORTED.to_vec() });
+                APP.lock().unwrap().track_request(request_id, peer, paths.join(", "), RequestKind::Bundle(paths));
+            }
+            Command::OfferFile { path, peer } => {
+                request_response_behaviour::offer_file(&self.finished_sender, peer, path);
+            }
+            Command::RequestPreview { path, peer } => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer, Request::Preview { path: path.clone(), lines: preview::DEFAULT_PREVIEW_LINES });
+                APP.lock().unwrap().track_request(request_id, peer, path, RequestKind::Preview(preview::DEFAULT_PREVIEW_LINES));
+            }
+            Command::CancelTransfer { hash, peer } => {
+                request_response_behaviour::cancel_transfer(&mut self.swarm, peer, hash);
+            }
+            Command::ListFiles { peer } => {
+                self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer, Request::ListFiles);
+            }
+            Command::RejectFile { request, reason, channel } => {
+                logger::info!("Rejected request for {}: {}", request, reason);
+                let response = Response::Denied { filename: request, reason };
+                if let Err(e) = self.swarm.behaviour_mut().request_response.send_response(channel, response) {
                     logger::error!("Failed to send response: {:?}", e);
                 }
-
+            }
+            Command::RespondFile { request } => {
+                request_response_behaviour::respond(&mut self.swarm, &self.finished_sender, request);
             }
             Command::GetUsername { peer_id } => {
                 // Get's a username based on a peer_id, ensuring it is added to the "app.username" hashmap for use throughout the app
@@ -368,6 +692,20 @@ impl EventLoop {
                 logger::info!("No errors in storing username");
 
             }
+            Command::Search { query } => {
+                // Look up the peers providing each keyword of the query, or a file hash given in full
+                let mut search = Search::new(&query);
+                let hash_keys: Vec<kad::RecordKey> = search.terms.iter()
+                    .filter(|term| term.len() == 64 && term.chars().all(|c| c.is_ascii_hexdigit()))
+                    .map(|hash| kademlia_behaviour::file_key(hash))
+                    .collect();
+                let keyword_keys = share::keywords(&query).into_iter()
+                    .map(|keyword| kademlia_behaviour::keyword_key(&keyword));
+                for key in hash_keys.into_iter().chain(keyword_keys) {
+                    search.queries.insert(self.swarm.behaviour_mut().kademlia.get_providers(key));
+                }
+                APP.lock().unwrap().search = Some(search);
+            }
             Command::GetRooms {  } => {
                 let key = kad::RecordKey::new(&"room_store".to_string());
                 self.swarm.behaviour_mut().kademlia.get_record(key);
@@ -378,8 +716,8 @@ impl EventLoop {
                 let key = kad::RecordKey::new(&"room_store".to_string());
                 let record = self.swarm.behaviour_mut().kademlia.store_mut().get(&key);
 
-                if !record.is_none() {
-                    let mut room_store: Vec<String> = match serde_cbor::from_slice(&record.unwrap().value) {
+                if let Some(record) = record {
+                    let mut room_store: Vec<String> = match serde_cbor::from_slice(&record.value) {
                         Ok(room_store) => room_store,
                         Err(e) => {
                             logger::info!("Failed to deserialize room list: {:?}", e);
@@ -401,8 +739,7 @@ impl EventLoop {
                         logger::info!("Room Store already contains: {:?}", chat_name.clone());
                     }
                 } else {
-                    let mut room_store = Vec::new();
-                    room_store.push(chat_name.clone());
+                    let room_store = vec![chat_name.clone()];
                     let rooms_bytes = serde_cbor::to_vec(&room_store).unwrap();
     
                     let record = kad::Record {
@@ -425,6 +762,8 @@ impl EventLoop {
 #[derive(NetworkBehaviour)]
 pub struct Behaviour {
     pub request_response: request_response::cbor::Behaviour<Request, Response>,
+    pub file_transfer: request_response::cbor::Behaviour<ChunkRequest, ChunkResponse>,
+    pub chat_sync: request_response::cbor::Behaviour<HistoryRequest, HistoryResponse>,
     pub kademlia: kad::Behaviour<kad::store::MemoryStore>,
     pub gossipsub: gossipsub::Behaviour,
     pub mdns: mdns::tokio::Behaviour,
@@ -440,13 +779,40 @@ enum Command {
         request: String,
         peer: PeerId,
     },
-    RespondFile {
+    RequestHash {
+        hash: String,
         filename: String,
-        filepath: String,
+        peer: PeerId,
+    },
+    ListFiles {
+        peer: PeerId,
+    },
+    RequestBundle {
+        paths: Vec<String>,
+        peer: PeerId,
+    },
+    OfferFile {
+        path: String,
+        peer: PeerId,
+    },
+    RequestPreview {
+        path: String,
+        peer: PeerId,
+    },
+    CancelTransfer {
+        hash: String,
+        peer: PeerId,
+    },
+    RespondFile {
+        request: RequestItem,
+    },
+    RejectFile {
+        request: String,
+        reason: String,
         channel: ResponseChannel<Response>
     },
     SendMessage {
-        message: String,
+        message: ChatMessage,
         topic: IdentTopic,
     },
     PushUsername {
@@ -455,6 +821,9 @@ enum Command {
     GetUsername {
         peer_id: String
     },
+    Search {
+        query: String,
+    },
     GetRooms {},
     CreateRoom {
         chat_name: String
@@ -462,13 +831,98 @@ enum Command {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Request {
-    pub request: String,
+pub enum Request {
+    /// Asks for a file by its path relative to the provider's share directory
+    File {
+        request: String,
+        /// Compression the requester can decode, from which the provider chooses one for each file
+        #[serde(default)]
+        compression: Vec<Compression>,
+    },
+    /// Asks for a file by its content hash, to download it from several providers at once
+    Hash {
+        hash: String,
+        #[serde(default)]
+        compression: Vec<Compression>,
+    },
+    /// Asks for several files or directories as one transfer, recursing into directories
+    Bundle {
+        paths: Vec<String>,
+        #[serde(default)]
+        compression: Vec<Compression>,
+    },
+    /// Offers to send a file, which the receiver downloads by its hash if they accept
+    Offer {
+        filename: String,
+        digest: FileDigest,
+    },
+    /// Asks for the list of files in the provider's share directory
+    ListFiles,
+    /// Asks for a preview of a shared file, with up to `lines` lines if it is text
+    Preview {
+        path: String,
+        lines: usize,
+    },
+    /// Tells the other side of a transfer that it has been cancelled, by the hash of the file
+    Cancel {
+        hash: String,
+    },
 }
 
 
+/// Reply to a `Request` for a file.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Response {
-    pub filename: String,
-    pub data: Vec<u8>,
-}
\ No newline at end of file
+pub enum Response {
+    /// Metadata for the offered file, whose contents are then fetched in chunks and verified against the digest
+    File {
+        filename: String,
+        digest: FileDigest,
+    },
+    /// The request was refused, for example because it named a path outside the share directory
+    Denied {
+        filename: String,
+        reason: String,
+    },
+    /// Nothing is shared at the requested path, or with the requested hash
+    NotFound {
+        filename: String,
+        reason: String,
+    },
+    /// The requested file or bundle is larger than the provider sends, both in bytes
+    TooLarge {
+        filename: String,
+        size: u64,
+        limit: u64,
+    },
+    /// The provider failed to read the requested file
+    InternalError {
+        filename: String,
+        reason: String,
+    },
+    /// Manifest of the files in a requested bundle, each of which is then fetched like a single file
+    Bundle {
+        manifest: Manifest,
+    },
+    /// The offered file was accepted, and its chunks will be requested
+    OfferAccepted {
+        /// Compression the receiver can decode
+        #[serde(default)]
+        compression: Vec<Compression>,
+    },
+    /// The request was not accepted or rejected before it expired
+    Expired {
+        filename: String,
+    },
+    /// The files available in the provider's share directory
+    FileList {
+        files: Vec<SharedFileInfo>,
+    },
+    /// Metadata and the start of the contents of a requested file
+    Preview {
+        preview: FilePreview,
+    },
+    /// The cancellation of a transfer was received
+    Cancelled {
+        hash: String,
+    },
+}
@@ -0,0 +1,71 @@
+use libp2p::{request_response, PeerId, Swarm};
+use libp2p_request_response::Message;
+use crate::chat::{HistoryRequest, HistoryResponse, MAX_SYNC_MESSAGES};
+use crate::logger;
+use crate::network::network::Behaviour;
+use crate::state::APP;
+
+/// Handles events from the chat history protocol.
+///
+/// Answers requests for the recent messages of a room from the messages this peer has, and merges
+/// the messages received in reply into the room, skipping any already there, not signed by their
+/// sender for that room, or older than asked for.
+pub async fn handle_event(
+    event: libp2p::request_response::Event<HistoryRequest, HistoryResponse>, swarm: &mut Swarm<Behaviour>
+) {
+    match event {
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            logger::info!("Inbound history Error from {}: {}", peer, error);
+        }
+
+        request_response::Event::OutboundFailure { peer, request_id, error } => {
+            logger::info!("Outbound history Failure to {}: {}", peer, error);
+            APP.lock().unwrap().history_requests.remove(&request_id);
+        }
+
+        request_response::Event::Message { peer, message } => {
+            match message {
+                // Only rooms are shared, as direct conversations are between their two peers alone
+                Message::Request { request, channel, .. } => {
+                    let messages = if request.room.len() <= 64 {
+                        APP.lock().unwrap().recent_messages(&request.room, request.since, request.limit.min(MAX_SYNC_MESSAGES))
+                    } else {
+                        Vec::new()
+                    };
+                    logger::info!("Sending {} messages of {} to {}", messages.len(), request.room, peer);
+                    let response = HistoryResponse { room: request.room, messages };
+                    if swarm.behaviour_mut().chat_sync.send_response(channel, response).is_err() {
+                        logger::error!("Failed to send history to {}", peer);
+                    }
+                }
+
+                // Only messages signed for the requested room, and within the requested time, are
+                // merged, so members cannot replay messages from elsewhere into the room
+                Message::Response { request_id, response } => {
+                    let mut app = APP.lock().unwrap();
+                    let Some(request) = app.history_requests.remove(&request_id) else {
+                        return;
+                    };
+                    let (room, count) = (response.room.clone(), response.messages.len());
+                    let Some(messages) = response.accepted(&request).filter(|_| app.rooms.contains(&request.room)) else {
+                        logger::error!("Dropping history of {} from {}, which asked for {}", room, peer, request.room);
+                        return;
+                    };
+                    let added = app.merge_messages(&room, messages);
+                    logger::info!("Received {} messages of {} from {}, {} of them new", count, room, peer, added);
+                }
+            }
+        }
+
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+/// Asks a peer in a room for the messages sent to it recently, within the history sync limits.
+pub fn request_history(swarm: &mut Swarm<Behaviour>, peer: PeerId, room: &str) {
+    let mut app = APP.lock().unwrap();
+    let request = app.history_request(room);
+    logger::info!("Requesting history of {} from {}", room, peer);
+    let request_id = swarm.behaviour_mut().chat_sync.send_request(&peer, request.clone());
+    app.history_requests.insert(request_id, request);
+}
@@ -0,0 +1,194 @@
+use libp2p::{request_response, PeerId, Swarm};
+use libp2p_request_response::{Message, OutboundFailure, ResponseChannel};
+use crate::logger;
+use crate::network::network::Behaviour;
+use crate::network::bandwidth::{Direction, SCHEDULER};
+use crate::network::compression::{self, Compression};
+use crate::network::transfer::{self, ChunkRequest, ChunkResponse, CHUNK_SIZE};
+use crate::state::APP;
+use crate::policy::RatioAction;
+
+/// Handles events from the chunked file transfer protocol.
+///
+/// Serves chunks of files that have been accepted for sharing with the requesting peer, and writes
+/// received chunks to disk while keeping every source of each download busy.
+pub async fn handle_event(
+    event: libp2p::request_response::Event<ChunkRequest, ChunkResponse>, swarm: &mut Swarm<Behaviour>
+) {
+    match event {
+        // Handles inbound failures by logging the error
+        request_response::Event::InboundFailure { error, .. } => {
+            logger::info!("Inbound chunk Error: {}", error);
+        }
+
+        // Handles outbound failures by retrying timed out chunks, possibly from another source, or
+        // dropping the source until it is reachable again
+        request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+            logger::info!("Outbound chunk Failure: {}", error);
+            let mut app = APP.lock().unwrap();
+            let Some(download) = app.downloads.values_mut().find(|download| download.in_flight.contains_key(&request_id)) else {
+                return;
+            };
+
+            if let OutboundFailure::Timeout = error {
+                let (index, _) = download.in_flight.remove(&request_id).unwrap();
+                download.requeue(index);
+            } else {
+                logger::info!("Lost source {} for download of {:?}", peer, download.filename);
+                download.remove_source(&peer, true);
+            }
+            download.request_chunks(swarm);
+        }
+
+        request_response::Event::Message { peer, message } => {
+            match message {
+                // Handles chunk requests by serving them straight away, or queueing them for the
+                // scheduler while uploads are rate limited, or while other peers are downloading if
+                // the peer is below the minimum swap ratio
+                Message::Request { request, channel, .. } => {
+                    let (deprioritised, len) = {
+                        let app = APP.lock().unwrap();
+                        let deprioritised = app.ratio_policy.action == RatioAction::Deprioritise && app.ratio_policy.is_below(&app.ledger.balance(&peer));
+                        let len = app.uploads.iter()
+                            .find(|upload| upload.peer == peer && upload.hash == request.file_id)
+                            .map_or(CHUNK_SIZE, |upload| transfer::chunk_len(upload.size, request.index));
+                        (deprioritised, len)
+                    };
+                    let mut scheduler = SCHEDULER.lock().unwrap();
+                    if scheduler.is_limited(Direction::Upload) || (deprioritised && scheduler.others_active(peer)) {
+                        scheduler.queue_chunk(peer, request, channel, len, deprioritised);
+                    } else {
+                        if !deprioritised {
+                            scheduler.record_served(peer);
+                        }
+                        drop(scheduler);
+                        serve_chunk(swarm, peer, request, channel);
+                    }
+                }
+
+                // Handles chunk responses by writing the chunk and requesting the next ones
+                Message::Response { request_id, response } => {
+                    let mut app = APP.lock().unwrap();
+                    match response {
+                        ChunkResponse::Chunk { file_id, index, data, compression } => {
+                            let Some(download) = app.downloads.get_mut(&file_id) else {
+                                logger::info!("Received chunk for unknown download {}", file_id);
+                                return;
+                            };
+                            download.in_flight.remove(&request_id);
+
+                            // Chunks which cannot be decompressed are treated like corrupted ones below
+                            let data = compression::decompress_chunk(compression, data).unwrap_or_else(|e| {
+                                logger::error!("Failed to decompress chunk {} of {:?}: {}", index, download.filename, e);
+                                Vec::new()
+                            });
+
+                            // Another source may already have sent this chunk
+                            if download.has_chunk(index) {
+                                download.request_chunks(swarm);
+                                return;
+                            }
+
+                            // Discard chunks that do not match the provider's hash and request them again
+                            if !download.verify_chunk(index, &data) {
+                                logger::error!("Chunk {} of {:?} from {} failed verification", index, download.filename, peer);
+                                if download.record_corrupt_chunk(index, &peer) {
+                                    logger::error!("Dropping source {} for {:?}, too many corrupted chunks", peer, download.filename);
+                                }
+                                download.request_chunks(swarm);
+                                return;
+                            }
+
+                            if let Err(e) = download.write_chunk(index, &data) {
+                                logger::error!("Error writing file {:?}: {:?}", download.filename, e);
+                                app.downloads.remove(&file_id);
+                                return;
+                            }
+
+                            let complete = download.is_complete();
+                            if !complete {
+                                download.request_chunks(swarm);
+                            }
+                            app.ledger.record_download(peer, data.len() as u64);
+                            if complete {
+                                let download = app.downloads.remove(&file_id).unwrap();
+                                app.finish_download(download);
+                            }
+                        }
+
+                        // Drops the source, keeping the partial file so it can be resumed from other providers
+                        ChunkResponse::Unavailable { file_id } => {
+                            logger::error!("{} no longer shares {}", peer, file_id);
+                            if let Some(download) = app.downloads.get_mut(&file_id) {
+                                download.remove_source(&peer, false);
+                                download.request_chunks(swarm);
+                            }
+                        }
+
+                        // Stops downloading from a provider which cancelled the transfer
+                        ChunkResponse::Cancelled { file_id } => {
+                            app.cancelled_by_peer(peer, &file_id);
+                            if let Some(download) = app.downloads.get_mut(&file_id) {
+                                download.request_chunks(swarm);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Answers a chunk request by reading the chunk from the shared file, if it was offered to the peer.
+pub fn serve_chunk(swarm: &mut Swarm<Behaviour>, peer: PeerId, request: ChunkRequest, channel: ResponseChannel<ChunkResponse>) {
+    let filepath = {
+        let app = APP.lock().unwrap();
+        app.shared_files.get(&request.file_id)
+            .filter(|_| app.grants.contains(&(peer, request.file_id.clone())))
+            .cloned()
+    };
+    let response = match filepath.map(|path| transfer::read_chunk(&path, request.index)) {
+        Some(Ok(data)) => {
+            let chosen = {
+                let mut app = APP.lock().unwrap();
+                let index = app.uploads.iter().position(|upload| upload.peer == peer && upload.hash == request.file_id);
+                index.map_or(Compression::None, |index| {
+                    app.ledger.record_upload(peer, data.len() as u64);
+                    let upload = &mut app.uploads[index];
+                    upload.record_chunk(request.index, data.len() as u64);
+                    let compression = upload.compression;
+                    if upload.is_complete() {
+                        app.log_upload(index);
+                    }
+                    compression
+                })
+            };
+            let (data, compression) = compression::compress_chunk(chosen, data);
+            ChunkResponse::Chunk {
+                file_id: request.file_id,
+                index: request.index,
+                data,
+                compression,
+            }
+        }
+        Some(Err(e)) => {
+            logger::error!("Failed to read chunk {} of {}: {}", request.index, request.file_id, e);
+            ChunkResponse::Unavailable { file_id: request.file_id }
+        }
+        None => {
+            let cancelled = APP.lock().unwrap().uploads.iter()
+                .any(|upload| upload.peer == peer && upload.hash == request.file_id && upload.cancelled);
+            if cancelled {
+                ChunkResponse::Cancelled { file_id: request.file_id }
+            } else {
+                logger::error!("Chunk requested for unshared file {}", request.file_id);
+                ChunkResponse::Unavailable { file_id: request.file_id }
+            }
+        }
+    };
+
+    if let Err(e) = swarm.behaviour_mut().file_transfer.send_response(channel, response) {
+        logger::error!("Failed to send chunk: {:?}", e);
+    }
+}
@@ -1,45 +1,74 @@
-use libp2p::gossipsub;
+use libp2p::{gossipsub, Swarm};
+use crate::chat::ChatMessage;
 use crate::logger;
+use crate::network::network::Behaviour;
+use crate::network::network_behaviour::chat_sync_behaviour;
+use crate::network::outbox;
 use crate::state::APP;
 
 /// Handles events from the gossipsub protocol and updates the application state accordingly.
 ///
 /// This function processes incoming messages from the gossipsub protocol and
-/// categorizes them as either public or private messages based on the topic name.
-pub async fn handle_event(event: libp2p::gossipsub::Event) {
-    match event {
-        // Handle incoming gossipsub messages
-        gossipsub::Event::Message {
-            message,
-            ..
-        } => {
-            logger::info!("In the swarm behaviour for receiving");
-
-            // Convert message data to a string
-            let msg = String::from_utf8_lossy(&message.data);
-            let topic_name = message.topic.as_str();
-            let final_msg = format!("{}", msg);
-
-            let mut app = APP.lock().unwrap();
-
-            // Check if the topic name length is within the allowed limit for room names
-            if topic_name.to_string().len() <= 64 {
-                // Insert the message into the public messages vector for the room
-                app.public_messages
-                    .entry(topic_name.to_string())
-                    .or_insert_with(Vec::new)
-                    .push(final_msg.clone());
-            } else {
-                // Otherwise, treat it as a private message
-                app.private_messages
-                    .entry(topic_name.to_string())
-                    .or_insert_with(Vec::new)
-                    .push(final_msg.clone());
+/// categorizes them as either public or private messages based on the topic name,
+/// recording them in the chat log.
+/// Messages which do not come from the peer that signed them, or were signed for another topic, are dropped. When another peer is
+/// found to be in one of this peer's rooms, it is asked for the room's recent messages, and when it
+/// joins a direct conversation, the messages queued for it are sent.
+pub async fn handle_event(event: libp2p::gossipsub::Event, swarm: &mut Swarm<Behaviour>) {
+    // Sync the history of rooms shared with a peer, which may have messages sent before this peer
+    // joined, and send the direct messages queued while the peer was away
+    if let gossipsub::Event::Subscribed { peer_id, topic } = event {
+        let topic = topic.as_str();
+        if topic.len() > 64 {
+            if topic.split('_').any(|member| member == peer_id.to_string()) {
+                outbox::deliver_queued(swarm, topic);
             }
+        } else if APP.lock().unwrap().rooms.iter().any(|joined| joined == topic) {
+            chat_sync_behaviour::request_history(swarm, peer_id, topic);
+        }
+        return;
+    }
+
+    // Handle incoming gossipsub messages
+    if let gossipsub::Event::Message {
+        message,
+        ..
+    } = event {
+        logger::info!("In the swarm behaviour for receiving");
+
+        let chat_message = match ChatMessage::decode(&message.data) {
+            Ok(chat_message) => chat_message,
+            Err(e) => {
+                logger::error!("Dropping message which could not be decoded: {:?}", e);
+                return;
+            }
+        };
+
+        // The sender named in the message has to be the peer which signed it
+        let source = message.source.map(|peer_id| peer_id.to_string());
+        if source.as_deref() != Some(chat_message.sender.as_str()) {
+            logger::error!("Dropping message {} claiming to be from {} but signed by {:?}", chat_message.id, chat_message.sender, source);
+            return;
+        }
+        let topic_name = message.topic.as_str();
+
+        // Messages are signed with the topic they were sent to, so they cannot be replayed into another
+        if chat_message.topic != topic_name || !chat_message.verify() {
+            logger::error!("Dropping message {} signed for {:?} but received on {}", chat_message.id, chat_message.topic, topic_name);
+            return;
+        }
+
+        let mut app = APP.lock().unwrap();
+
+        // Messages to a topic too long to be a room name are private, and only the two peers in
+        // the topic may send them
+        if topic_name.len() > 64 && !topic_name.split('_').any(|peer_id| peer_id == chat_message.sender) {
+            logger::error!("Dropping private message {} from {}, who is not part of {}", chat_message.id, chat_message.sender, topic_name);
+            return;
+        }
+        app.add_message(topic_name, chat_message.clone());
 
-            // Log the received message
-            logger::info!("Received message: {}", final_msg);
-        },
-        _ => {}
+        // Log the received message
+        logger::info!("Received message {} from {}", chat_message.id, chat_message.sender);
     }
-}
\ No newline at end of file
+}
@@ -1,84 +1,194 @@
-use libp2p::{Swarm, kad};
+use libp2p::{PeerId, Swarm, kad};
 use crate::logger;
 use crate::network::network::Behaviour;
+use crate::network::network_behaviour::chat_sync_behaviour;
+use crate::network::outbox;
 use std::collections::HashSet;
 use libp2p::gossipsub::IdentTopic;
 use crate::APP;
+use crate::state::RequestKind;
+use crate::network::network::Request;
+use crate::network::compression;
+
+/// Prefix of the provider record keys for shared files' hashes.
+const FILE_KEY_PREFIX: &str = "swapbytes/file/";
+
+/// Returns the provider record key for a shared file's hash.
+pub fn file_key(hash: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{}{}", FILE_KEY_PREFIX, hash))
+}
+
+/// Prefix of the record keys for direct messages parked for a peer who is away.
+const PARKED_KEY_PREFIX: &str = "swapbytes/parked/";
+
+/// Returns the record key for the direct messages a sender has parked for a recipient.
+pub fn parked_key(recipient: &PeerId, sender: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{}{}/{}", PARKED_KEY_PREFIX, recipient, sender))
+}
+
+/// Returns the provider record key the peers which have parked messages for a recipient provide,
+/// so the recipient can find them without knowing who they are.
+pub fn parked_index_key(recipient: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{}{}", PARKED_KEY_PREFIX, recipient))
+}
+
+// Returns the recipient and sender of parked messages from their record key
+fn parked_peers(key: &kad::RecordKey) -> Option<(PeerId, PeerId)> {
+    let (recipient, sender) = std::str::from_utf8(key.as_ref()).ok()?.strip_prefix(PARKED_KEY_PREFIX)?.split_once('/')?;
+    Some((recipient.parse().ok()?, sender.parse().ok()?))
+}
+
+/// Returns the provider record key for a normalised file name keyword.
+pub fn keyword_key(keyword: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("swapbytes/keyword/{}", keyword))
+}
 
 /// Handles Kademlia (kad) events and updates the swarm and application state accordingly.
 ///
 /// This function processes various query results from the Kademlia protocol, including
-/// record retrieval and storage operations.
+/// record retrieval and storage operations, and provider lookups for file searches.
 pub async fn handle_event(
     event: libp2p::kad::Event, swarm: &mut Swarm<Behaviour>
 ) {
-    match event {
-        // Handle outbound query progress
-        kad::Event::OutboundQueryProgressed { result, .. } => {
-            match result {
-                // Handle successful record retrieval
-                kad::QueryResult::GetRecord(Ok(
-                    kad::GetRecordOk::FoundRecord(kad::PeerRecord {
-                        record: kad::Record { key, value, .. },
-                        ..
-                    })
-                )) => {
-                    // Attempt to deserialize the record value into a username
-                    if let Ok(username) = serde_cbor::from_slice::<String>(&value) {
-                        logger::info!(
-                            "Got record {:?} {:?}", 
-                            std::str::from_utf8(key.as_ref()).unwrap(),
-                            username.clone(),
-                        );
-                        
-                        // Update application state with the retrieved username
-                        let mut app = APP.lock().unwrap();
-                        app.usernames.insert(std::str::from_utf8(key.as_ref()).unwrap().to_string(), username);
-                        
-                        // Remove peer without username if it exists
-                        if !app.peers_no_username.is_empty() && app.peers_no_username.iter().any(|&i| i.to_string() == std::str::from_utf8(key.as_ref()).unwrap().to_string()) {
-                            let index = app.peers_no_username.iter().position(|x| *x.to_string() == std::str::from_utf8(key.as_ref()).unwrap().to_string()).unwrap();
-                            app.peers_no_username.remove(index);
-                        }
-                    } 
-                    // Attempt to deserialize the record value into a list of rooms
-                    else if let Ok(room_store) = serde_cbor::from_slice::<Vec<String>>(&value) {                        
-                        let mut app = APP.lock().unwrap();
-                        let mut room_set: HashSet<String> = app.rooms.clone().iter().cloned().collect();
-                        
-                        for room in room_store {
-                            // Add new rooms to the application state and subscribe to gossipsub topics
-                            if room_set.insert(room.clone()) {
-                                let topic = IdentTopic::new(room.clone());
-                                
-                                if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
-                                    logger::error!("Failed to subscribe to gossipsub topic {}: {}", room.clone(), e);
-                                } else {
-                                    logger::info!("Subscribed to gossipsub topic: {}", room.clone());
-                                    app.public_messages.insert(room.clone(), Vec::new());
-                                    app.rooms.push(room);
-                                }
+    // Handle outbound query progress
+    if let kad::Event::OutboundQueryProgressed { id, result, step, .. } = event {
+        // Forget the peers asked by a provider lookup once it is over
+        if step.last {
+            APP.lock().unwrap().source_queries.remove(&id);
+        }
+        match result {
+            // Handle successful record retrieval
+            kad::QueryResult::GetRecord(Ok(
+                kad::GetRecordOk::FoundRecord(kad::PeerRecord {
+                    record: kad::Record { key, value, .. },
+                    ..
+                })
+            )) => {
+                // Messages parked for this peer by a peer who is away
+                if let Some((recipient, sender)) = parked_peers(&key) {
+                    outbox::receive_parked(&recipient, &sender, &value);
+                }
+                // Attempt to deserialize the record value into a username
+                else if let Ok(username) = serde_cbor::from_slice::<String>(&value) {
+                    logger::info!(
+                        "Got record {:?} {:?}", 
+                        std::str::from_utf8(key.as_ref()).unwrap(),
+                        username.clone(),
+                    );
+                    
+                    // Update application state with the retrieved username
+                    let mut app = APP.lock().unwrap();
+                    app.usernames.insert(std::str::from_utf8(key.as_ref()).unwrap().to_string(), username);
+                    
+                    // Remove peer without username if it exists
+                    if !app.peers_no_username.is_empty() && app.peers_no_username.iter().any(|&i| i.to_string() == std::str::from_utf8(key.as_ref()).unwrap()) {
+                        let index = app.peers_no_username.iter().position(|x| *x.to_string() == *std::str::from_utf8(key.as_ref()).unwrap()).unwrap();
+                        app.peers_no_username.remove(index);
+                    }
+                } 
+                // Attempt to deserialize the record value into a list of rooms
+                else if let Ok(room_store) = serde_cbor::from_slice::<Vec<String>>(&value) {                        
+                    let mut app = APP.lock().unwrap();
+                    let mut room_set: HashSet<String> = app.rooms.clone().iter().cloned().collect();
+                    let mut joined = Vec::new();
+                    
+                    for room in room_store {
+                        // Add new rooms to the application state and subscribe to gossipsub topics
+                        if room_set.insert(room.clone()) {
+                            let topic = IdentTopic::new(room.clone());
+                            
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                                logger::error!("Failed to subscribe to gossipsub topic {}: {}", room.clone(), e);
+                            } else {
+                                logger::info!("Subscribed to gossipsub topic: {}", room.clone());
+                                app.public_messages.entry(room.clone()).or_default();
+                                app.rooms.push(room.clone());
+                                joined.push(room);
                             }
                         }
-                    } else {
-                        logger::error!("Error deserializing: Invalid data format");
                     }
+                    drop(app);
+
+                    // Ask the peers already in newly joined rooms for their recent messages
+                    let members: Vec<(PeerId, String)> = swarm.behaviour().gossipsub.all_peers()
+                        .flat_map(|(peer_id, topics)| topics.into_iter().map(move |topic| (*peer_id, topic.to_string())))
+                        .filter(|(_, topic)| joined.contains(topic))
+                        .collect();
+                    for (peer_id, room) in members {
+                        chat_sync_behaviour::request_history(swarm, peer_id, &room);
+                    }
+                } else {
+                    logger::error!("Error deserializing: Invalid data format");
                 }
-                
-                // Handle other query results
-                kad::QueryResult::GetRecord(Ok(_)) => {}
-                kad::QueryResult::GetRecord(Err(err)) => {
-                    logger::info!("Failed to get record {:?}", err);
+            }
+            
+            // Handle other query results
+            kad::QueryResult::GetRecord(Ok(_)) => {}
+            kad::QueryResult::GetRecord(Err(err)) => {
+                logger::info!("Failed to get record {:?}", err);
+            }
+            kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
+                logger::info!("Successfully put record {:?}", std::str::from_utf8(key.as_ref()).unwrap());
+                if let Some((recipient, _)) = parked_peers(&key) {
+                    outbox::parked(&recipient);
                 }
-                kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
-                    logger::info!("Successfully put record {:?}", std::str::from_utf8(key.as_ref()).unwrap());
+            }
+            kad::QueryResult::PutRecord(Err(err)) => {
+                logger::error!("Failed to put record: {:?}", err);
+            }
+
+            // Handle peers found to share a file being downloaded by asking them to provide it too,
+            // and peers found to share a searched keyword or hash by asking for their file lists
+            kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { key, providers })) => {
+                // Peers which have parked messages for this peer
+                let my_peer_id = *swarm.local_peer_id();
+                if key == parked_index_key(&my_peer_id) {
+                    outbox::found_parked(swarm, &my_peer_id, providers);
+                    return;
                 }
-                kad::QueryResult::PutRecord(Err(err)) => {
-                    logger::error!("Failed to put record: {:?}", err);
+
+                let key = std::str::from_utf8(key.as_ref()).unwrap_or_default().to_string();
+                logger::info!("Found {} providers for {:?}", providers.len(), key);
+                let mut app = APP.lock().unwrap();
+
+                if let Some(download) = key.strip_prefix(FILE_KEY_PREFIX).and_then(|hash| app.downloads.get(hash)) {
+                    let (hash, filename) = (download.digest.hash.clone(), download.filename.clone());
+                    // Lost sources are resumed when they become reachable again, so they are not asked again here
+                    let new_sources: Vec<PeerId> = providers.iter()
+                        .filter(|peer| **peer != my_peer_id && !download.sources.contains(peer) && !download.lost_sources.contains(peer))
+                        .copied()
+                        .collect();
+                    let asked = app.source_queries.entry(id).or_default();
+                    let new_sources: Vec<PeerId> = new_sources.into_iter().filter(|peer| asked.insert(*peer)).collect();
+                    for peer in new_sources {
+                        logger::info!("Asking {} to also provide {:?}", peer, filename);
+                        let request_id = swarm.behaviour_mut().request_response.send_request(&peer, Request::Hash { hash: hash.clone(), compression: compression::SUPPORTED.to_vec() });
+                        app.track_request(request_id, peer, filename.clone(), RequestKind::File);
+                    }
                 }
-                _ => {}
+
+                // Only lookups started by the current search add to its results
+                let Some(search) = app.search.as_mut().filter(|search| search.queries.contains(&id)) else {
+                    return;
+                };
+                for peer in providers {
+                    if peer != my_peer_id && !search.providers.contains(&peer) {
+                        search.providers.push(peer);
+                        swarm.behaviour_mut().request_response.send_request(&peer, Request::ListFiles);
+                    }
+                }
+            }
+            kad::QueryResult::GetProviders(Ok(_)) => {}
+            kad::QueryResult::GetProviders(Err(err)) => {
+                logger::info!("Failed to get providers {:?}", err);
+            }
+            kad::QueryResult::StartProviding(Ok(kad::AddProviderOk { key })) => {
+                logger::info!("Providing {:?}", std::str::from_utf8(key.as_ref()).unwrap_or_default());
+            }
+            kad::QueryResult::StartProviding(Err(err)) => {
+                logger::info!("Failed to announce provider record: {:?}", err);
             }
+            _ => {}
         }
-        _ => {}
     }
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
 use libp2p::{Swarm, mdns};
 use crate::logger;
 use crate::network::network::Behaviour;
+use crate::network::outbox;
+use crate::network::transfer;
 use crate::state::APP;
 use libp2p::gossipsub::IdentTopic;
 
@@ -11,6 +13,7 @@ pub async fn handle_event(event: libp2p::mdns::Event, swarm: &mut Swarm<Behaviou
     match event {
         // Handle discovered peers
         mdns::Event::Discovered(list) => {
+            let first_peers = APP.lock().unwrap().connected_peers == 0;
             for (peer_id, multiaddr) in list {
                 logger::info!("mDNS discovered peer: {}", peer_id);
                 
@@ -20,15 +23,16 @@ pub async fn handle_event(event: libp2p::mdns::Event, swarm: &mut Swarm<Behaviou
                 
                 // Update the application state with the discovered peer
                 let mut app = APP.lock().unwrap();
-                app.peers.push(peer_id.clone());
-                app.peers_no_username.push(peer_id.clone());
+                app.peers.push(peer_id);
+                app.away_peers.retain(|away| *away != peer_id);
+                app.peers_no_username.push(peer_id);
                 app.connected_peers += 1;
                 
                 // Create a gossipsub topic for direct messaging between peers
                 let peer_id_str = peer_id.to_string();
                 let my_peer_id = swarm.local_peer_id().clone().to_string();
                 // Construct the message key using the sorted peer IDs
-                let mut peer_ids = vec![peer_id_str.clone(), my_peer_id.clone()];
+                let mut peer_ids = [peer_id_str.clone(), my_peer_id.clone()];
                 peer_ids.sort(); // Sort alphabetically
                 let topic_name = peer_ids.join("_");
                 // Create a gossipsub topic from the combined string
@@ -39,8 +43,19 @@ pub async fn handle_event(event: libp2p::mdns::Event, swarm: &mut Swarm<Behaviou
                     logger::error!("Failed to subscribe to gossipsub topic {}: {}", topic_name, e);
                 } else {
                     logger::info!("Subscribed to gossipsub topic: {}", topic_name);
-                    app.private_messages.insert(topic_name, Vec::new());
-                }  
+                    app.private_messages.entry(topic_name).or_default();
+                }
+
+                // Resume any downloads that were paused when this peer went away
+                transfer::resume_downloads(&mut app.downloads, &peer_id, swarm);
+            }
+
+            // Look for messages parked while this peer was away, and for providers of downloads
+            // left without a source, as soon as the DHT can be reached
+            if first_peers {
+                outbox::pick_up(swarm);
+                let app = APP.lock().unwrap();
+                transfer::find_missing_sources(&app.downloads, swarm);
             }
         }
 
@@ -55,6 +70,7 @@ pub async fn handle_event(event: libp2p::mdns::Event, swarm: &mut Swarm<Behaviou
                 // Update the application state to reflect the expired peer
                 let mut app = APP.lock().unwrap();
                 app.connected_peers -= 1;
+                transfer::pause_downloads(&mut app.downloads, &peer_id, swarm);
             }
         }
     }
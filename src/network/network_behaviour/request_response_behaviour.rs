@@ -1,52 +1,595 @@
-use libp2p::request_response;
-use libp2p_request_response::Message;
+use futures::channel::mpsc;
+use libp2p::{request_response, PeerId, Swarm};
+use libp2p_request_response::{Message, OutboundFailure, OutboundRequestId, ResponseChannel};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use crate::logger;
-use crate::state::{APP, RequestItem};
-use crate::network::network::{Request, Response};
+use crate::state::{App, APP, RequestItem, RequestKind, RequestStatus};
+use crate::network::network::{Behaviour, Request, Response};
+use crate::share;
+use crate::network::network_behaviour::kademlia_behaviour;
+use crate::network::bundle::{Bundle, Manifest, ManifestEntry};
+use crate::network::bandwidth::SCHEDULER;
+use crate::network::compression::{self, Compression};
+use crate::network::transfer::{self, ChunkResponse, Download, DownloadOutcome, FileDigest, FinishedDownload, Upload};
+use crate::policy::{PolicyRequest, RatioAction};
+use crate::preview::FilePreview;
+use crate::share::ShareError;
+
+/// Work on a request which was finished off the event loop, waiting to be sent by the swarm.
+pub enum Finished {
+    /// A response to a request from a peer
+    Response(ResponseChannel<Response>, Response),
+    /// An offer of a shared file to a peer, sent once the file has been hashed
+    Offer { peer: PeerId, filename: String, digest: FileDigest },
+}
+
+/// Hands work finished off the event loop back to it.
+pub type FinishedSender = mpsc::UnboundedSender<Finished>;
+
+/// Sends the result of work which was finished off the event loop.
+pub fn send_finished(swarm: &mut Swarm<Behaviour>, finished: Finished) {
+    match finished {
+        Finished::Response(channel, response) => {
+            if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, response) {
+                logger::error!("Failed to send response: {:?}", e);
+            }
+        }
+        Finished::Offer { peer, filename, digest } => {
+            let request_id = swarm
+                .behaviour_mut()
+                .request_response
+                .send_request(&peer, Request::Offer { filename: filename.clone(), digest: digest.clone() });
+            APP.lock().unwrap().track_request(request_id, peer, filename, RequestKind::Offer(digest));
+        }
+    }
+}
 
 /// Handles events from the request-response protocol.
 ///
 /// Processes different types of events such as inbound and outbound failures, and incoming messages.
-pub async fn handle_event(event: libp2p::request_response::Event<Request, Response>) {
+pub async fn handle_event(
+    event: libp2p::request_response::Event<Request, Response>, swarm: &mut Swarm<Behaviour>, finished: &FinishedSender
+) {
     match event {
         // Handles inbound failures by logging the error
         request_response::Event::InboundFailure { error, .. } => {
             logger::info!("Inbound Error: {}", error);
         }
 
-        // Handles outbound failures by logging the error
-        request_response::Event::OutboundFailure { error, .. } => {
+        // Handles outbound failures by logging the error and marking the request as timed out or failed
+        request_response::Event::OutboundFailure { request_id, error, .. } => {
             logger::info!("Outbound Failure: {}", error);
+            let status = match error {
+                OutboundFailure::Timeout => RequestStatus::TimedOut,
+                error => RequestStatus::Failed(error.to_string()),
+            };
+            APP.lock().unwrap().set_request_status(request_id, status);
         }
 
         // Handles incoming messages
         request_response::Event::Message { peer, message } => {
             match message {
-                // Handles requests by logging the request and adding it to the current requests list
+                // Handles file requests by logging the request and adding it to the current requests list,
+                // and answers requests for the file list straight away
                 Message::Request { request, channel, .. } => {
                     logger::info!("Received request: {:?}", request);
-                    let mut app = APP.lock().unwrap();
-                    let new_request = RequestItem {
-                        peer_id: peer,
-                        request_string: request.request,
-                        response_channel: channel,
-                    };
-                    app.current_requests.push(new_request);
+                    match request {
+                        // Rules are matched against the file the request resolves to, so an alias such as
+                        // a symlink or `./` prefix cannot get around a path rule
+                        Request::File { request, compression } => {
+                            let files = requested_file(&request);
+                            approve_or_queue(swarm, finished, request_item(peer, request, RequestKind::File, compression, channel), files);
+                        }
+                        // Bundles are approved automatically only if the policy approves every file in them
+                        Request::Bundle { paths, compression } => {
+                            let share_dir = APP.lock().unwrap().share_dir.clone();
+                            let files = share::resolve_bundle(&share_dir, &paths)
+                                .map(|files| files.into_iter()
+                                    .map(|(_, path)| {
+                                        let size = std::fs::metadata(&path).ok().map(|metadata| metadata.len());
+                                        (share::shared_name(&share_dir, &path), size)
+                                    })
+                                    .collect())
+                                .unwrap_or_default();
+                            approve_or_queue(swarm, finished, request_item(peer, paths.join(", "), RequestKind::Bundle(paths), compression, channel), files);
+                        }
+                        // Requests for a file by hash are queued under the name of the matching shared
+                        // file, found in the catalogue kept by the event loop rather than by hashing the
+                        // share directory for every request
+                        Request::Hash { hash, compression } => {
+                            let file = share::CATALOGUE.lock().unwrap().iter().find(|file| file.hash == hash).cloned();
+                            match file {
+                                Some(file) => {
+                                    let files = vec![(file.name.clone(), Some(file.size))];
+                                    approve_or_queue(swarm, finished, request_item(peer, file.name, RequestKind::File, compression, channel), files);
+                                }
+                                None => {
+                                    let response = Response::NotFound { filename: hash, reason: "File is not shared".to_string() };
+                                    if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, response) {
+                                        logger::error!("Failed to send response: {:?}", e);
+                                    }
+                                }
+                            }
+                        }
+                        // Offers are always left for the user to accept, since they were not asked for
+                        Request::Offer { filename, digest } => {
+                            let request = request_item(peer, filename, RequestKind::Offer(digest), Vec::new(), channel);
+                            APP.lock().unwrap().current_requests.push(request);
+                        }
+                        Request::ListFiles => {
+                            let files = share::CATALOGUE.lock().unwrap().clone();
+                            if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, Response::FileList { files }) {
+                                logger::error!("Failed to send file list: {:?}", e);
+                            }
+                        }
+                        // Cancellations stop the transfer straight away, whichever side it was sent from
+                        Request::Cancel { hash } => {
+                            logger::info!("{} cancelled the transfer of {}", peer, hash);
+                            drop_queued_chunks(swarm, peer, &hash);
+                            APP.lock().unwrap().cancelled_by_peer(peer, &hash);
+                            if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, Response::Cancelled { hash }) {
+                                logger::error!("Failed to send response: {:?}", e);
+                            }
+                        }
+                        // Previews show part of the file's contents, so are approved like a request for the file
+                        Request::Preview { path, lines } => {
+                            let files = requested_file(&path);
+                            approve_or_queue(swarm, finished, request_item(peer, path, RequestKind::Preview(lines), Vec::new(), channel), files);
+                        }
+                    }
                 },
 
-                // Handles responses by logging the response and saving the file data
-                Message::Response { response, .. } => {
-                    logger::info!("Received response: {:?}", response);
+                // Handles responses by starting a chunked download of the offered file or of every file in
+                // an offered bundle, or adding this provider as a source of a download of the same content
+                Message::Response { request_id, response } => {
+                    let (filename, digest) = match response {
+                        Response::File { filename, digest } => {
+                            APP.lock().unwrap().set_request_status(request_id, RequestStatus::Accepted);
+                            (filename, digest)
+                        }
+                        Response::Bundle { manifest } => {
+                            APP.lock().unwrap().set_request_status(request_id, RequestStatus::Accepted);
+                            start_bundle(swarm, peer, manifest);
+                            return;
+                        }
+                        // Compress the offered file now the receiver has said what it can decode
+                        Response::OfferAccepted { compression } => {
+                            let mut app = APP.lock().unwrap();
+                            app.set_request_status(request_id, RequestStatus::Accepted);
+                            let offered = app.sent_requests.iter()
+                                .find(|request| request.request_id == request_id)
+                                .and_then(|request| match &request.kind {
+                                    RequestKind::Offer(digest) => Some(digest.hash.clone()),
+                                    _ => None,
+                                });
+                            let Some(hash) = offered else {
+                                return;
+                            };
+                            let chosen = app.shared_files.get(&hash)
+                                .map_or(Compression::None, |path| compression::choose(&compression, path));
+                            if let Some(upload) = app.uploads.iter_mut().find(|upload| upload.peer == peer && upload.hash == hash) {
+                                upload.compression = chosen;
+                            }
+                            return;
+                        }
+                        Response::FileList { files } => {
+                            logger::info!("Received list of {} files from {}", files.len(), peer);
+                            let mut app = APP.lock().unwrap();
 
-                    // Write the response data to a file
-                    if let Err(e) = std::fs::write("new_".to_owned() + &response.filename, response.data) {
-                        logger::error!("Error writing file {:?}: {:?}", &response.filename, e);
-                    } else {
-                        logger::info!("File {:?} received and saved successfully", &response.filename);
-                    }
+                            // Pick out the matching files if the list was requested by a search
+                            if let Some(search) = app.search.as_mut().filter(|search| search.providers.contains(&peer)) {
+                                search.add_results(peer, &files);
+                            }
+                            app.catalogues.insert(peer, files);
+                            return;
+                        }
+                        Response::Preview { preview } => {
+                            logger::info!("Received preview of {:?} from {}", preview.filename, peer);
+                            let mut app = APP.lock().unwrap();
+                            app.set_request_status(request_id, RequestStatus::Accepted);
+
+                            // The preview comes from the peer, so only show it if it is well formed
+                            if preview.is_valid() {
+                                app.preview = Some((peer, preview));
+                            } else {
+                                logger::error!("Dropping malformed preview of {:?} from {}", preview.filename, peer);
+                            }
+                            return;
+                        }
+                        Response::Cancelled { .. } => return,
+                        Response::Expired { filename } => {
+                            record_refusal(request_id, peer, filename, RequestStatus::TimedOut, "Timed out".to_string());
+                            return;
+                        }
+                        Response::Denied { filename, reason } => {
+                            let failure = format!("Denied: {}", reason);
+                            record_refusal(request_id, peer, filename, RequestStatus::Rejected(reason), failure);
+                            return;
+                        }
+                        Response::NotFound { filename, reason } => {
+                            let failure = format!("Not found: {}", reason);
+                            record_refusal(request_id, peer, filename, RequestStatus::NotFound(reason), failure);
+                            return;
+                        }
+                        Response::TooLarge { filename, size, limit } => {
+                            let failure = "Too large for the provider".to_string();
+                            record_refusal(request_id, peer, filename, RequestStatus::TooLarge { size, limit }, failure);
+                            return;
+                        }
+                        Response::InternalError { filename, reason } => {
+                            let failure = format!("Provider error: {}", reason);
+                            record_refusal(request_id, peer, filename, RequestStatus::Failed(failure.clone()), failure);
+                            return;
+                        }
+                    };
+                    logger::info!("Received response: {} {:?}", filename, digest.hash);
+                    let mut app = APP.lock().unwrap();
+                    let path = transfer::download_path(&app.download_dir, &filename);
+                    start_download(&mut app, swarm, peer, filename, digest, path);
                 },
             }
         }
         _ => {}
     }
 }
+
+// Starts downloading a file to a path, or adds the peer as a source if the same file is already
+// being downloaded
+fn start_download(app: &mut App, swarm: &mut Swarm<Behaviour>, peer: PeerId, filename: String, digest: FileDigest, path: PathBuf) {
+    let hash = digest.hash.clone();
+
+    // Another provider of a file already being downloaded becomes an additional source
+    if let Some(download) = app.downloads.get_mut(&hash) {
+        logger::info!("Adding source {} for download of {:?}", peer, download.filename);
+        if let Err(e) = download.add_source(peer) {
+            logger::error!("Error updating partial file {:?}: {:?}", &filename, e);
+        }
+        download.request_chunks(swarm);
+        return;
+    }
+
+    // A partial file kept when the file was last cancelled is resumed rather than started again
+    let download = match transfer::kept_partial(&app.download_dir, &hash) {
+        Some(mut download) => {
+            logger::info!("Resuming kept partial download of {:?}", download.filename);
+            download.add_source(peer).map(|_| download)
+        }
+        None => path.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| Download::new(filename.clone(), digest, peer, path)),
+    };
+    match download {
+        Ok(mut download) => {
+            if download.is_complete() {
+                app.finish_download(download);
+                return;
+            }
+            download.request_chunks(swarm);
+
+            // Look for other peers sharing the same file to download from in parallel
+            swarm.behaviour_mut().kademlia.get_providers(kademlia_behaviour::file_key(&hash));
+            app.downloads.insert(hash, download);
+        }
+        Err(e) => {
+            logger::error!("Error writing file {:?}: {:?}", &filename, e);
+            app.record_outcome(&hash, Path::new(""), &DownloadOutcome::Failed(e.to_string()));
+        }
+    }
+}
+
+// Starts downloading every distinct file of a bundle, hashing its manifest again here so the bundle
+// is tracked under a hash which matches its entries
+fn start_bundle(swarm: &mut Swarm<Behaviour>, peer: PeerId, manifest: Manifest) {
+    let manifest = Manifest::new(manifest.name, manifest.requested, manifest.entries);
+
+    logger::info!("Received manifest for {:?} with {} files", manifest.name, manifest.entries.len());
+    let mut app = APP.lock().unwrap();
+    let bundle = Bundle::new(manifest, peer, &app.download_dir);
+    let mut files: Vec<(String, FileDigest, PathBuf)> = Vec::new();
+    for entry in &bundle.manifest.entries {
+        if files.iter().all(|(_, digest, _)| digest.hash != entry.digest.hash) {
+            files.push((entry.path.clone(), entry.digest.clone(), bundle.destinations[&entry.path].clone()));
+        }
+    }
+
+    // Register the bundle first, so files which finish straight away are recorded in it
+    app.bundles.insert(bundle.manifest.hash.clone(), bundle);
+    for (filename, digest, path) in files {
+        start_download(&mut app, swarm, peer, filename, digest, path);
+    }
+}
+
+// Records a refused file request, listing it as a failed download so it can be retried unless it
+// asked for another source of a download already in progress or offered a file
+fn record_refusal(request_id: OutboundRequestId, peer: PeerId, filename: String, status: RequestStatus, failure: String) {
+    logger::error!("Request for {:?} refused: {}", filename, failure);
+    let mut app = APP.lock().unwrap();
+    app.set_request_status(request_id, status);
+    let kind = app.sent_requests.iter()
+        .find(|request| request.request_id == request_id)
+        .map(|request| request.kind.clone());
+    let bundle = match kind {
+        Some(RequestKind::Offer(_) | RequestKind::Preview(_)) => return,
+        Some(RequestKind::Bundle(paths)) => Some(paths),
+        _ => None,
+    };
+    if !app.downloads.contains_key(&filename) {
+        app.finished_downloads.push(FinishedDownload {
+            filename,
+            peers: vec![peer],
+            hash: String::new(),
+            size: 0,
+            outcome: DownloadOutcome::Failed(failure),
+            bundle,
+        });
+    }
+}
+
+// Finds the shared name and size of the file a request resolves to, for the auto-accept policy to match
+fn requested_file(request: &str) -> Vec<(String, Option<u64>)> {
+    let share_dir = APP.lock().unwrap().share_dir.clone();
+    share::resolve(&share_dir, request).ok()
+        .map(|path| {
+            let size = std::fs::metadata(&path).ok().map(|metadata| metadata.len());
+            (share::shared_name(&share_dir, &path), size)
+        })
+        .into_iter()
+        .collect()
+}
+
+// Creates a queued request which has just arrived
+fn request_item(peer: PeerId, request: String, kind: RequestKind, compression: Vec<Compression>, channel: ResponseChannel<Response>) -> RequestItem {
+    RequestItem {
+        peer_id: peer,
+        request_string: request,
+        kind,
+        compression,
+        response_channel: channel,
+        received: Instant::now(),
+    }
+}
+
+// Answers a request straight away if the auto-accept policy approves every file it asks for,
+// otherwise queues it for the user to accept or reject. Peers below the minimum swap ratio are
+// always asked about, or refused outright if the ratio policy says so
+fn approve_or_queue(swarm: &mut Swarm<Behaviour>, finished: &FinishedSender, request: RequestItem, files: Vec<(String, Option<u64>)>) {
+    let refusal = {
+        let app = APP.lock().unwrap();
+        let balance = app.ledger.balance(&request.peer_id);
+        let below_ratio = app.ratio_policy.is_below(&balance);
+        if below_ratio && app.ratio_policy.action == RatioAction::Refuse {
+            Some(format!(
+                "Swap ratio {:.2} is below {:.2}",
+                balance.ratio().unwrap_or_default(),
+                app.ratio_policy.min_ratio.unwrap_or_default(),
+            ))
+        } else {
+            None
+        }
+    };
+    if let Some(reason) = refusal {
+        logger::info!("Refusing request from {} for {:?}: {}", request.peer_id, request.request_string, reason);
+        let response = Response::Denied { filename: request.request_string, reason };
+        if let Err(e) = swarm.behaviour_mut().request_response.send_response(request.response_channel, response) {
+            logger::error!("Failed to send response: {:?}", e);
+        }
+        return;
+    }
+
+    {
+        let mut app = APP.lock().unwrap();
+        let below_ratio = app.ratio_policy.is_below(&app.ledger.balance(&request.peer_id));
+        let approved = !below_ratio && !files.is_empty() && files.iter().all(|(path, size)| {
+            app.accept_policy.approves(&PolicyRequest {
+                peer: request.peer_id,
+                path,
+                size: *size,
+            })
+        });
+        if !approved {
+            app.current_requests.push(request);
+            return;
+        }
+    }
+
+    logger::info!("Automatically accepting request from {} for {:?}", request.peer_id, request.request_string);
+    respond(swarm, finished, request);
+}
+
+/// Offers a requested file or bundle to a peer, sends it a preview, or accepts a file the peer offered.
+///
+/// Resolves the request within the share directory, allows the peer to request the chunks of the files
+/// and sends their metadata through the request's response channel. Files are hashed and previews built
+/// in the background, as they read whole files, and the response is sent once ready. Accepted offers
+/// start downloading from the peer straight away.
+pub fn respond(swarm: &mut Swarm<Behaviour>, finished: &FinishedSender, request: RequestItem) {
+    let RequestItem { peer_id: peer, request_string, kind, compression, response_channel, .. } = request;
+    match kind {
+        RequestKind::Bundle(paths) => {
+            respond_in_background(finished, response_channel, move || bundle_response(peer, request_string, &paths, &compression));
+        }
+        RequestKind::File => {
+            respond_in_background(finished, response_channel, move || file_response(peer, request_string, &compression));
+        }
+        RequestKind::Preview(lines) => {
+            respond_in_background(finished, response_channel, move || preview_response(request_string, lines));
+        }
+        RequestKind::Offer(digest) => {
+            let mut app = APP.lock().unwrap();
+            let path = transfer::download_path(&app.download_dir, &request_string);
+            start_download(&mut app, swarm, peer, request_string, digest, path);
+            drop(app);
+
+            // Attempt to send the response and log any errors that occur.
+            let response = Response::OfferAccepted { compression: compression::SUPPORTED.to_vec() };
+            if let Err(e) = swarm.behaviour_mut().request_response.send_response(response_channel, response) {
+                logger::error!("Failed to send response: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Offers a file from the share directory to a peer.
+///
+/// The file is hashed in the background and shared with the peer before the offer is sent, so its
+/// chunks can be requested as soon as the peer accepts. The share is withdrawn if the offer is
+/// rejected or expires.
+pub fn offer_file(finished: &FinishedSender, peer: PeerId, path: String) {
+    let share_dir = APP.lock().unwrap().share_dir.clone();
+    let finished = finished.clone();
+    tokio::task::spawn_blocking(move || {
+        let filepath = match share::resolve(&share_dir, &path) {
+            Ok(filepath) => filepath,
+            Err(e) => {
+                logger::error!("Cannot offer {:?}: {}", path, e);
+                return;
+            }
+        };
+        let digest = match share::digest(&filepath) {
+            Ok(digest) => digest,
+            Err(e) => {
+                logger::error!("Failed to read file {:?}: {}", filepath, e);
+                return;
+            }
+        };
+
+        let filename = filepath.file_name().unwrap_or_default().to_string_lossy().to_string();
+        grant_file(peer, filename.clone(), filepath, &digest, &[]);
+        let _ = finished.unbounded_send(Finished::Offer { peer, filename, digest });
+    });
+}
+
+/// Stops a transfer of a file with a peer which was cancelled here, telling the peer to stop its side.
+///
+/// Chunks of the file waiting for upload bandwidth are answered straight away, so they no longer take
+/// a turn in the scheduler.
+pub fn cancel_transfer(swarm: &mut Swarm<Behaviour>, peer: PeerId, hash: String) {
+    drop_queued_chunks(swarm, peer, &hash);
+    swarm.behaviour_mut().request_response.send_request(&peer, Request::Cancel { hash });
+}
+
+// Answers the chunk requests for a file queued in the scheduler, telling the peer the transfer was cancelled
+fn drop_queued_chunks(swarm: &mut Swarm<Behaviour>, peer: PeerId, hash: &str) {
+    let dropped = SCHEDULER.lock().unwrap().cancel(peer, hash);
+    for (_, request, channel) in dropped {
+        let response = ChunkResponse::Cancelled { file_id: request.file_id };
+        if let Err(e) = swarm.behaviour_mut().file_transfer.send_response(channel, response) {
+            logger::error!("Failed to send chunk: {:?}", e);
+        }
+    }
+}
+
+// Builds a response on a blocking thread, for requests which read whole files, and hands it back to
+// the event loop to send
+fn respond_in_background(finished: &FinishedSender, channel: ResponseChannel<Response>, build: impl FnOnce() -> Response + Send + 'static) {
+    let finished = finished.clone();
+    tokio::task::spawn_blocking(move || {
+        let _ = finished.unbounded_send(Finished::Response(channel, build()));
+    });
+}
+
+// Hashes a shared file, answering with an internal error if it cannot be read
+fn digest(filepath: &Path, filename: &str) -> Result<FileDigest, Response> {
+    share::digest(filepath).map_err(|e| {
+        logger::error!("Failed to read file {:?}: {}", filepath, e);
+        Response::InternalError { filename: filename.to_string(), reason: "Failed to read file".to_string() }
+    })
+}
+
+// Answers with an error if a file or bundle is larger than this peer sends
+fn check_size(filename: &str, size: u64) -> Result<(), Response> {
+    match APP.lock().unwrap().max_file_size {
+        Some(limit) if size > limit => {
+            logger::error!("Refused request for {}: {} bytes is over the {} byte limit", filename, size, limit);
+            Err(Response::TooLarge { filename: filename.to_string(), size, limit })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Describes why a requested path cannot be served
+fn share_error_response(filename: String, error: ShareError) -> Response {
+    logger::error!("Refused request for {}: {}", filename, error);
+    match error {
+        ShareError::Denied(reason) => Response::Denied { filename, reason },
+        ShareError::NotFound(e) => Response::NotFound { filename, reason: e.to_string() },
+    }
+}
+
+// Shares a file so the peer can request its chunks by hash over the file transfer protocol,
+// compressed if the peer can decode it and the file is worth compressing
+fn grant_file(peer: PeerId, filename: String, filepath: PathBuf, digest: &FileDigest, accepted: &[Compression]) {
+    let mut upload = Upload::new(filename, digest, peer);
+    upload.compression = compression::choose(accepted, &filepath);
+
+    let mut app = APP.lock().unwrap();
+    app.shared_files.insert(digest.hash.clone(), filepath);
+    app.grants.insert((peer, digest.hash.clone()));
+    app.uploads.retain(|upload| upload.peer != peer || upload.hash != digest.hash);
+    app.uploads.push(upload);
+}
+
+// Builds the manifest of every file in a bundle, sharing each of them with the peer once every
+// file has been read
+fn bundle_response(peer: PeerId, name: String, paths: &[String], accepted: &[Compression]) -> Response {
+    let share_dir = APP.lock().unwrap().share_dir.clone();
+    let files = match share::resolve_bundle(&share_dir, paths) {
+        Ok(files) if files.is_empty() => {
+            return Response::NotFound { filename: name, reason: "No files to send".to_string() };
+        }
+        Ok(files) => files,
+        Err(e) => return share_error_response(name, e),
+    };
+
+    let mut entries = Vec::new();
+    for (path, filepath) in &files {
+        match digest(filepath, &name) {
+            Ok(digest) => entries.push(ManifestEntry { path: path.clone(), digest }),
+            Err(response) => return response,
+        }
+    }
+    let manifest = Manifest::new(name, paths.to_vec(), entries);
+    if let Err(response) = check_size(&manifest.name, manifest.size()) {
+        return response;
+    }
+
+    for ((path, filepath), entry) in files.into_iter().zip(&manifest.entries) {
+        grant_file(peer, path, filepath, &entry.digest, accepted);
+    }
+    Response::Bundle { manifest }
+}
+
+// Shares a single file with the peer and describes it
+fn file_response(peer: PeerId, request: String, accepted: &[Compression]) -> Response {
+    let share_dir = APP.lock().unwrap().share_dir.clone();
+    let filepath = match share::resolve(&share_dir, &request) {
+        Ok(filepath) => filepath,
+        Err(e) => return share_error_response(request, e),
+    };
+    let digest = match digest(&filepath, &request).and_then(|digest| check_size(&request, digest.size).map(|_| digest)) {
+        Ok(digest) => digest,
+        Err(response) => return response,
+    };
+
+    let filename = filepath.file_name().unwrap_or_default().to_string_lossy().to_string();
+    grant_file(peer, filename.clone(), filepath, &digest, accepted);
+    Response::File { filename, digest }
+}
+
+// Describes a shared file and the start of its contents without sharing it
+fn preview_response(path: String, lines: usize) -> Response {
+    let share_dir = APP.lock().unwrap().share_dir.clone();
+    let filepath = match share::resolve(&share_dir, &path) {
+        Ok(filepath) => filepath,
+        Err(e) => return share_error_response(path, e),
+    };
+    let filename = filepath.file_name().unwrap_or_default().to_string_lossy().to_string();
+    match FilePreview::build(&filepath, filename, lines) {
+        Ok(preview) => Response::Preview { preview },
+        Err(e) => {
+            logger::error!("Failed to preview file {:?}: {}", filepath, e);
+            Response::InternalError { filename: path, reason: "Failed to read file".to_string() }
+        }
+    }
+}
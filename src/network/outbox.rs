@@ -0,0 +1,366 @@
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use libp2p::gossipsub::IdentTopic;
+use libp2p::identity::Keypair;
+use libp2p::{kad, PeerId, Swarm};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use crate::chat::{self, ChatMessage, DeliveryState};
+use crate::logger;
+use crate::network::network::Behaviour;
+use crate::network::network_behaviour::kademlia_behaviour;
+use crate::state::{App, APP};
+
+/// How often the DHT is checked for messages parked by peers who are away.
+pub const PICK_UP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long messages parked in the DHT are kept for.
+const PARK_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most messages parked in the DHT for a peer at once, the newest being kept if there are more.
+const MAX_PARKED_MESSAGES: usize = 50;
+
+/// Direct messages parked in the DHT, encrypted so only the peer they were sent to can read them.
+///
+/// The messages are encrypted with a key agreed between a new X25519 key and the recipient's
+/// Ed25519 key taken as an X25519 key, so they need nothing from the recipient but its peer ID.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SealedMessages {
+    /// Public half of the X25519 key made for these messages
+    ephemeral: [u8; 32],
+    /// CBOR list of the messages, encrypted with ChaCha20-Poly1305
+    ciphertext: Vec<u8>,
+}
+
+/// Sealed messages as stored in the DHT, signed by the peer which parked them, as anyone could
+/// store a record under the key the recipient looks them up by.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ParkedRecord {
+    /// CBOR encoded `SealedMessages`
+    sealed: Vec<u8>,
+    /// Sender's signature over the record key and the sealed messages
+    signature: Vec<u8>,
+}
+
+// Encodes the fields covered by a parked record's signature, tying the messages to the record key
+// so they cannot be moved to another sender's or recipient's key
+fn parked_bytes(key: &kad::RecordKey, sealed: &[u8]) -> Vec<u8> {
+    serde_cbor::to_vec(&(key.as_ref(), sealed)).unwrap_or_default()
+}
+
+// Derives the encryption key for sealed messages from the agreed secret and both public keys
+fn sealing_key(shared: &MontgomeryPoint, ephemeral: &MontgomeryPoint, recipient: &MontgomeryPoint) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(shared.as_bytes());
+    hasher.update(ephemeral.as_bytes());
+    hasher.update(recipient.as_bytes());
+    Key::clone_from_slice(&hasher.finalize())
+}
+
+// Encrypts messages so only the peer they are sent to can read them
+fn seal(recipient: &PeerId, messages: &[ChatMessage]) -> Option<SealedMessages> {
+    let recipient = chat::peer_public_key(recipient)?.try_into_ed25519().ok()?;
+    let recipient = CompressedEdwardsY(recipient.to_bytes()).decompress()?.to_montgomery();
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let ephemeral = MontgomeryPoint::mul_base_clamped(secret);
+    let shared = recipient.mul_clamped(secret);
+
+    // Each key encrypts only one message list, so a fixed nonce is never reused with it
+    let plaintext = serde_cbor::to_vec(&messages).ok()?;
+    let cipher = ChaCha20Poly1305::new(&sealing_key(&shared, &ephemeral, &recipient));
+    let ciphertext = cipher.encrypt(&Nonce::default(), plaintext.as_slice()).ok()?;
+    Some(SealedMessages { ephemeral: ephemeral.to_bytes(), ciphertext })
+}
+
+// Decrypts messages sealed for this peer, using the X25519 form of its Ed25519 secret key
+fn open(keypair: &Keypair, sealed: &SealedMessages) -> Option<Vec<ChatMessage>> {
+    let keypair = keypair.clone().try_into_ed25519().ok()?;
+    let secret: [u8; 32] = Sha512::digest(keypair.secret().as_ref())[..32].try_into().ok()?;
+    let own = MontgomeryPoint::mul_base_clamped(secret);
+    let ephemeral = MontgomeryPoint(sealed.ephemeral);
+    let shared = ephemeral.mul_clamped(secret);
+
+    let cipher = ChaCha20Poly1305::new(&sealing_key(&shared, &ephemeral, &own));
+    let plaintext = cipher.decrypt(&Nonce::default(), sealed.ciphertext.as_slice()).ok()?;
+    serde_cbor::from_slice(&plaintext).ok()
+}
+
+// Returns the other peer of a direct conversation topic
+fn other_peer(topic: &str, my_peer_id: &PeerId) -> Option<PeerId> {
+    let my_peer_id = my_peer_id.to_string();
+    topic.split('_').find(|peer_id| *peer_id != my_peer_id)?.parse().ok()
+}
+
+// Publishes a message to its topic, returning false if no peer in the topic could be reached
+fn publish(swarm: &mut Swarm<Behaviour>, topic: &str, message: &ChatMessage) -> bool {
+    let bytes = match message.encode() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            // Sending it again would fail the same way, so it is not queued
+            logger::error!("Failed to encode message {}: {:?}", message.id, e);
+            return true;
+        }
+    };
+    match swarm.behaviour_mut().gossipsub.publish(IdentTopic::new(topic), bytes) {
+        Ok(_) => {
+            logger::info!("{} sent successfully.", message.id);
+            true
+        }
+        Err(e) => {
+            logger::info!("Failed to publish {} to {}: {:?}", message.id, topic, e);
+            false
+        }
+    }
+}
+
+/// Loads the direct messages which were still queued when the app last exited, by conversation topic.
+pub fn load(path: &Path) -> io::Result<HashMap<String, Vec<ChatMessage>>> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_cbor::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// Writes the queued direct messages to the outbox file, through a temporary file so the outbox is
+// not lost if the app is killed part way through
+fn save(app: &App) {
+    let Some(path) = &app.outbox_file else {
+        return;
+    };
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let written = serde_cbor::to_vec(&app.outbox)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .and_then(|bytes| std::fs::write(&temporary, bytes))
+        .and_then(|_| std::fs::rename(&temporary, path));
+    if let Err(e) = written {
+        logger::error!("Error writing outbox {:?}: {:?}", path, e);
+    }
+}
+
+/// Sends a message to a room or direct conversation.
+///
+/// Direct messages which cannot be published, because the other peer is away, are queued to be
+/// sent when it reappears, and are parked in the DHT for it to pick up if `park_messages` is set.
+pub fn send(swarm: &mut Swarm<Behaviour>, topic: &str, message: ChatMessage) {
+    let sent = publish(swarm, topic, &message);
+    if topic.len() <= 64 {
+        return;
+    }
+
+    let mut app = APP.lock().unwrap();
+    if sent {
+        app.delivery.insert(message.id, DeliveryState::Sent);
+        return;
+    }
+    logger::info!("Queueing message {} to {}", message.id, topic);
+    app.delivery.insert(message.id.clone(), DeliveryState::Queued);
+    app.outbox.entry(topic.to_string()).or_default().push(message);
+    save(&app);
+    if app.park_messages {
+        park(swarm, &app, topic);
+    }
+}
+
+/// Sends the messages queued for a direct conversation, such as once the other peer has been found
+/// again by mDNS and has rejoined the conversation. Messages which still cannot be sent stay queued.
+pub fn deliver_queued(swarm: &mut Swarm<Behaviour>, topic: &str) {
+    let queued = APP.lock().unwrap().outbox.remove(topic).unwrap_or_default();
+    if queued.is_empty() {
+        return;
+    }
+    logger::info!("Sending {} queued messages to {}", queued.len(), topic);
+
+    let mut unsent = Vec::new();
+    let mut sent = Vec::new();
+    for message in queued {
+        if publish(swarm, topic, &message) {
+            sent.push(message.id);
+        } else {
+            unsent.push(message);
+        }
+    }
+
+    let mut app = APP.lock().unwrap();
+    for id in sent {
+        app.delivery.insert(id, DeliveryState::Sent);
+    }
+    if !unsent.is_empty() {
+        app.outbox.entry(topic.to_string()).or_default().extend(unsent);
+    } else if let Some(recipient) = app.my_peer_id.and_then(|my_peer_id| other_peer(topic, &my_peer_id)) {
+        // Nothing is left for the peer to pick up
+        swarm.behaviour_mut().kademlia.stop_providing(&kademlia_behaviour::parked_index_key(&recipient));
+    }
+    save(&app);
+}
+
+// Stores the newest messages queued for a direct conversation in the DHT, encrypted for the other
+// peer, replacing any parked before
+fn park(swarm: &mut Swarm<Behaviour>, app: &App, topic: &str) {
+    let Some(my_peer_id) = app.my_peer_id else {
+        return;
+    };
+    let (Some(recipient), Some(queued)) = (other_peer(topic, &my_peer_id), app.outbox.get(topic)) else {
+        return;
+    };
+    let messages = &queued[queued.len().saturating_sub(MAX_PARKED_MESSAGES)..];
+    let key = kademlia_behaviour::parked_key(&recipient, &my_peer_id);
+    let value = seal(&recipient, messages)
+        .and_then(|sealed| serde_cbor::to_vec(&sealed).ok())
+        .zip(app.keypair.as_ref())
+        .and_then(|(sealed, keypair)| {
+            let signature = keypair.sign(&parked_bytes(&key, &sealed)).ok()?;
+            serde_cbor::to_vec(&ParkedRecord { sealed, signature }).ok()
+        });
+    let Some(value) = value else {
+        logger::error!("Failed to seal messages for {}", recipient);
+        return;
+    };
+
+    let record = kad::Record {
+        key,
+        value,
+        publisher: Some(my_peer_id),
+        expires: Some(Instant::now() + PARK_TTL),
+    };
+    if let Err(e) = swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One) {
+        logger::error!("Failed to park messages for {}: {:?}", recipient, e);
+        return;
+    }
+
+    // The recipient finds who has parked messages for it through provider records, as it may not
+    // know this peer yet when it starts
+    if let Err(e) = swarm.behaviour_mut().kademlia.start_providing(kademlia_behaviour::parked_index_key(&recipient)) {
+        logger::error!("Failed to announce messages parked for {}: {:?}", recipient, e);
+    }
+}
+
+/// Marks the messages queued for a peer as parked, once the DHT has stored them.
+pub fn parked(recipient: &PeerId) {
+    let mut app = APP.lock().unwrap();
+    let Some(my_peer_id) = app.my_peer_id else {
+        return;
+    };
+    let ids: Vec<String> = app.outbox.get(&chat::direct_topic(&my_peer_id, recipient))
+        .map(|queued| queued.iter().map(|message| message.id.clone()).collect())
+        .unwrap_or_default();
+    for id in ids {
+        if app.delivery.get(&id) == Some(&DeliveryState::Queued) {
+            app.delivery.insert(id, DeliveryState::Parked);
+        }
+    }
+}
+
+/// Looks in the DHT for the peers which have parked messages for this peer, and parks the queued
+/// messages which have not been parked yet, such as those restored from the outbox file.
+pub fn pick_up(swarm: &mut Swarm<Behaviour>) {
+    let app = APP.lock().unwrap();
+    let Some(my_peer_id) = app.my_peer_id else {
+        return;
+    };
+    swarm.behaviour_mut().kademlia.get_providers(kademlia_behaviour::parked_index_key(&my_peer_id));
+
+    if !app.park_messages {
+        return;
+    }
+    let unparked: Vec<String> = app.outbox.iter()
+        .filter(|(_, queued)| queued.iter().any(|message| app.delivery.get(&message.id) == Some(&DeliveryState::Queued)))
+        .map(|(topic, _)| topic.clone())
+        .collect();
+    for topic in unparked {
+        park(swarm, &app, &topic);
+    }
+}
+
+/// Fetches the messages parked for this peer by each peer found to have parked some.
+pub fn found_parked(swarm: &mut Swarm<Behaviour>, recipient: &PeerId, senders: impl IntoIterator<Item = PeerId>) {
+    for sender in senders.into_iter().filter(|sender| sender != recipient) {
+        logger::info!("{} has parked messages for this peer", sender);
+        swarm.behaviour_mut().kademlia.get_record(kademlia_behaviour::parked_key(recipient, &sender));
+    }
+}
+
+/// Adds the messages parked in the DHT for this peer to their conversation, skipping any already
+/// there and any not signed by the peer which parked them.
+///
+/// Records not signed by the peer whose key they are stored under are ignored, as any peer can
+/// store a record under that key.
+pub fn receive_parked(recipient: &PeerId, sender: &PeerId, value: &[u8]) {
+    let mut app = APP.lock().unwrap();
+    if app.my_peer_id.as_ref() != Some(recipient) {
+        return;
+    }
+    let key = kademlia_behaviour::parked_key(recipient, sender);
+    let Some(record) = serde_cbor::from_slice::<ParkedRecord>(value).ok().filter(|record| {
+        chat::peer_public_key(sender).is_some_and(|public| public.verify(&parked_bytes(&key, &record.sealed), &record.signature))
+    }) else {
+        logger::error!("Ignoring messages parked for {} which are not signed by {}", recipient, sender);
+        return;
+    };
+
+    let messages = serde_cbor::from_slice::<SealedMessages>(&record.sealed).ok()
+        .zip(app.keypair.as_ref())
+        .and_then(|(sealed, keypair)| open(keypair, &sealed));
+    let Some(messages) = messages else {
+        logger::error!("Failed to open messages parked by {}", sender);
+        return;
+    };
+
+    let sender_id = sender.to_string();
+    let messages = messages.into_iter()
+        .filter(|message| message.sender == sender_id && message.verify())
+        .collect();
+    let added = app.merge_messages(&chat::direct_topic(recipient, sender), messages);
+    logger::info!("Picked up {} new messages parked by {}", added, sender);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_recipient_opens_sealed_messages() {
+        let (alice, bob, eve) = (Keypair::generate_ed25519(), Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let topic = chat::direct_topic(&alice.public().to_peer_id(), &bob.public().to_peer_id());
+        let messages = vec![ChatMessage::from_input(&alice, &topic, "hello"), ChatMessage::from_input(&alice, &topic, "/me waves")];
+
+        let sealed = seal(&bob.public().to_peer_id(), &messages).unwrap();
+        let opened = open(&bob, &sealed).unwrap();
+        assert_eq!(opened.iter().map(|message| &message.id).collect::<Vec<_>>(), messages.iter().map(|message| &message.id).collect::<Vec<_>>());
+        assert!(opened.iter().all(ChatMessage::verify));
+
+        assert!(open(&eve, &sealed).is_none());
+        assert!(open(&alice, &sealed).is_none());
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(open(&bob, &tampered).is_none());
+    }
+
+    #[test]
+    fn parked_records_are_tied_to_their_key() {
+        let (alice, bob, eve) = (Keypair::generate_ed25519(), Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let (alice_id, bob_id, eve_id) = (alice.public().to_peer_id(), bob.public().to_peer_id(), eve.public().to_peer_id());
+        let key = kademlia_behaviour::parked_key(&bob_id, &alice_id);
+        let sealed = b"sealed messages".to_vec();
+        let signature = alice.sign(&parked_bytes(&key, &sealed)).unwrap();
+
+        let alice_key = chat::peer_public_key(&alice_id).unwrap();
+        assert!(alice_key.verify(&parked_bytes(&key, &sealed), &signature));
+        assert!(!alice_key.verify(&parked_bytes(&key, b"other messages"), &signature));
+        assert!(!alice_key.verify(&parked_bytes(&kademlia_behaviour::parked_key(&eve_id, &alice_id), &sealed), &signature));
+
+        let forged = eve.sign(&parked_bytes(&key, &sealed)).unwrap();
+        assert!(!alice_key.verify(&parked_bytes(&key, &sealed), &forged));
+    }
+}
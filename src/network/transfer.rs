@@ -0,0 +1,871 @@
+use libp2p::{PeerId, Swarm};
+use libp2p_request_response::OutboundRequestId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::network::network::Behaviour;
+use crate::network::bandwidth::{Direction, SCHEDULER};
+use crate::network::compression::Compression;
+use crate::network::network_behaviour::kademlia_behaviour;
+use crate::logger;
+use crate::history;
+
+/// Size of a single chunk sent over the `/file-transfer/1` protocol.
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Maximum number of chunk requests a download keeps in flight to each source at once.
+pub const MAX_IN_FLIGHT: usize = 8;
+
+/// Number of chunks failing verification before a source is dropped from a download.
+pub const MAX_CORRUPT_CHUNKS: u32 = 8;
+
+/// Period over which transfer speeds are averaged.
+pub const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Requests a single chunk of a file the provider has agreed to share, identified by its content hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRequest {
+    pub file_id: String,
+    pub index: u64,
+}
+
+/// Answers a `ChunkRequest` with either the chunk data or a refusal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChunkResponse {
+    Chunk {
+        file_id: String,
+        index: u64,
+        data: Vec<u8>,
+        /// Compression applied to the data, which is checked against the chunk's hash once decompressed
+        #[serde(default)]
+        compression: Compression,
+    },
+    Unavailable {
+        file_id: String,
+    },
+    /// The provider cancelled the transfer, so no more chunks of the file will be sent
+    Cancelled {
+        file_id: String,
+    },
+}
+
+/// SHA-256 digests of a file as a whole and of each of its chunks, hex encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileDigest {
+    pub hash: String,
+    pub chunk_hashes: Vec<String>,
+    pub size: u64,
+}
+
+/// Result of checking a completed download against its expected hash.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadOutcome {
+    Verified,
+    Corrupted,
+    Failed(String),
+}
+
+/// A download which has finished, kept so its outcome can be shown to the user.
+#[derive(Debug, Clone)]
+pub struct FinishedDownload {
+    pub filename: String,
+    /// Peers which provided chunks of the file
+    pub peers: Vec<PeerId>,
+    /// Hash of the file, or empty if the request was denied before it was known
+    pub hash: String,
+    pub size: u64,
+    pub outcome: DownloadOutcome,
+    /// Paths requested, if this was a bundle of files
+    pub bundle: Option<Vec<String>>,
+}
+
+/// Measures the rate bytes are transferred at over the last `THROUGHPUT_WINDOW`.
+#[derive(Debug, Clone, Default)]
+pub struct Throughput {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    /// Records bytes transferred just now.
+    pub fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes));
+        while self.samples.front().is_some_and(|(time, _)| now.duration_since(*time) > THROUGHPUT_WINDOW) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Returns the average number of bytes transferred per second over the window.
+    pub fn bytes_per_sec(&self) -> f64 {
+        let now = Instant::now();
+        let bytes: u64 = self.samples.iter()
+            .filter(|(time, _)| now.duration_since(*time) <= THROUGHPUT_WINDOW)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        bytes as f64 / THROUGHPUT_WINDOW.as_secs_f64()
+    }
+
+    /// Estimates how long the remaining bytes will take at the current rate.
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        let rate = self.bytes_per_sec();
+        (rate > 0.0).then(|| Duration::from_secs_f64(remaining as f64 / rate))
+    }
+
+    /// Returns the time of the last transfer within the window, if any.
+    pub fn last_activity(&self) -> Option<Instant> {
+        self.samples.back().map(|(time, _)| *time)
+    }
+}
+
+/// A file being served to a peer which has been allowed to request its chunks.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub filename: String,
+    pub hash: String,
+    pub peer: PeerId,
+    pub size: u64,
+    /// Chunks sent to the peer, which may be fewer than the whole file if it has other sources
+    pub sent_chunks: HashSet<u64>,
+    pub throughput: Throughput,
+    /// Set once the upload is cancelled, after which chunk requests are refused
+    pub cancelled: bool,
+    /// Compression chosen for the chunks sent to the peer
+    pub compression: Compression,
+    /// Seconds since the Unix epoch when the peer was allowed to request the file
+    pub started: u64,
+    /// Set once the upload has been recorded in the transfer history
+    pub logged: bool,
+}
+
+impl Upload {
+    /// Creates an upload of a shared file to a peer, before any chunk has been requested.
+    pub fn new(filename: String, digest: &FileDigest, peer: PeerId) -> Self {
+        Self {
+            filename,
+            hash: digest.hash.clone(),
+            peer,
+            size: digest.size,
+            sent_chunks: HashSet::new(),
+            throughput: Throughput::default(),
+            cancelled: false,
+            compression: Compression::None,
+            started: history::unix_now(),
+            logged: false,
+        }
+    }
+
+    /// Records a chunk sent to the peer.
+    pub fn record_chunk(&mut self, index: u64, len: u64) {
+        self.sent_chunks.insert(index);
+        self.throughput.record(len);
+    }
+
+    /// Returns the number of distinct bytes sent to the peer.
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_chunks.iter().map(|index| chunk_len(self.size, *index)).sum()
+    }
+
+    /// Returns true once every chunk of the file has been sent.
+    pub fn is_complete(&self) -> bool {
+        self.sent_chunks.len() as u64 == chunk_count(self.size)
+    }
+}
+
+/// On-disk record of a partially received file, stored next to it as `<file>.part.state`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartialState {
+    filename: String,
+    digest: FileDigest,
+    sources: Vec<String>,
+    path: PathBuf,
+    received: Vec<(u64, u64)>,
+    #[serde(default)]
+    started: u64,
+}
+
+/// Tracks a file being received chunk by chunk from one or more peers.
+///
+/// Chunks are requested from every source in parallel, each source being kept busy with up
+/// to `MAX_IN_FLIGHT` requests, so faster sources end up serving more of the file. Chunks are
+/// written to `<file>.part` and the received byte ranges are appended to `<file>.part.ranges`,
+/// with the digest and sources kept in a sidecar state file, so the download can be resumed after
+/// its sources disconnect or the app restarts.
+pub struct Download {
+    /// Name of the file as sent by the provider
+    pub filename: String,
+    /// Expected digests of the file and its chunks
+    pub digest: FileDigest,
+    /// Reachable peers which have agreed to provide the file
+    pub sources: Vec<PeerId>,
+    /// Sources which have disconnected, and are used again if they reappear
+    pub lost_sources: Vec<PeerId>,
+    /// Location the completed file is moved to
+    pub path: PathBuf,
+    /// Chunks which have not been requested yet
+    pub pending: VecDeque<u64>,
+    /// Chunks which have been requested but not yet received, and the source they were requested from
+    pub in_flight: HashMap<OutboundRequestId, (u64, PeerId)>,
+    /// Sorted, non-overlapping byte ranges written to disk
+    pub received: Vec<(u64, u64)>,
+    /// Number of chunks from each source which failed verification
+    pub corrupt_chunks: HashMap<PeerId, u32>,
+    /// Rate chunks are being written at
+    pub throughput: Throughput,
+    /// Seconds since the Unix epoch when the download was first started
+    pub started: u64,
+}
+
+impl Download {
+    /// Creates a download for a file and allocates its partial file on disk.
+    pub fn new(filename: String, digest: FileDigest, peer: PeerId, path: PathBuf) -> io::Result<Self> {
+        let download = Self {
+            filename,
+            pending: (0..chunk_count(digest.size)).collect(),
+            digest,
+            sources: vec![peer],
+            lost_sources: Vec::new(),
+            path,
+            in_flight: HashMap::new(),
+            received: Vec::new(),
+            corrupt_chunks: HashMap::new(),
+            throughput: Throughput::default(),
+            started: history::unix_now(),
+        };
+
+        let file = File::create(download.part_path())?;
+        file.set_len(download.digest.size)?;
+        download.save_state()?;
+        Ok(download)
+    }
+
+    /// Restores a paused download from its sidecar state file.
+    pub fn from_state(state_path: &Path) -> io::Result<Self> {
+        let state: PartialState = serde_cbor::from_slice(&std::fs::read(state_path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut download = Self {
+            filename: state.filename,
+            digest: state.digest,
+            sources: Vec::new(),
+            lost_sources: state.sources.iter().filter_map(|peer| peer.parse().ok()).collect(),
+            path: state.path,
+            pending: VecDeque::new(),
+            in_flight: HashMap::new(),
+            received: state.received,
+            corrupt_chunks: HashMap::new(),
+            throughput: Throughput::default(),
+            started: state.started,
+        };
+
+        // Ranges received since the state file was written are appended to the ranges log, which
+        // ends early if the app was killed while writing to it
+        let ranges = match std::fs::read(download.ranges_path()) {
+            Ok(ranges) => ranges,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for range in serde_cbor::Deserializer::from_slice(&ranges).into_iter::<(u64, u64)>() {
+            match range {
+                Ok((start, end)) => download.add_range(start, end),
+                Err(_) => break,
+            }
+        }
+        download.pending = download.missing_chunks().collect();
+        Ok(download)
+    }
+
+    /// Path of the file chunks are written to until the download completes.
+    pub fn part_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".part")
+    }
+
+    /// Path of the sidecar file recording the digest, sources and ranges received when it was written.
+    pub fn state_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".part.state")
+    }
+
+    /// Path of the log the ranges received since the state file was written are appended to.
+    pub fn ranges_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".part.ranges")
+    }
+
+    /// Returns the total number of bytes written to disk.
+    pub fn received_bytes(&self) -> u64 {
+        self.received.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// Returns true once every byte has been written to disk.
+    pub fn is_complete(&self) -> bool {
+        self.received_bytes() == self.digest.size
+    }
+
+    /// Returns true while there is no reachable source to request chunks from.
+    pub fn is_paused(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Returns every peer known to provide the file, reachable or not.
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.sources.iter().chain(&self.lost_sources).copied().collect()
+    }
+
+    /// Returns true if a chunk has already been written to disk.
+    pub fn has_chunk(&self, index: u64) -> bool {
+        let start = index * CHUNK_SIZE;
+        let end = (start + CHUNK_SIZE).min(self.digest.size);
+        self.received.iter().any(|&(s, e)| s <= start && e >= end)
+    }
+
+    /// Returns the indices of chunks which are not yet fully written to disk.
+    pub fn missing_chunks(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..chunk_count(self.digest.size)).filter(|index| !self.has_chunk(*index))
+    }
+
+    /// Returns true if a received chunk matches the provider's hash for it.
+    pub fn verify_chunk(&self, index: u64, data: &[u8]) -> bool {
+        self.digest.chunk_hashes.get(index as usize) == Some(&hash_bytes(data))
+    }
+
+    /// Writes a received chunk to the partial file and appends its range to the ranges log.
+    ///
+    /// Only the range is written for each chunk, as rewriting the state file with every chunk hash
+    /// each time would take longer the larger the file.
+    pub fn write_chunk(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        write_chunk(&self.part_path(), index, data)?;
+
+        let start = index * CHUNK_SIZE;
+        let end = start + data.len() as u64;
+        self.add_range(start, end);
+        self.throughput.record(data.len() as u64);
+
+        let range = serde_cbor::to_vec(&(start, end)).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        OpenOptions::new().create(true).append(true).open(self.ranges_path())?.write_all(&range)
+    }
+
+    // Records a range of bytes as received, merging it with the ranges next to it
+    fn add_range(&mut self, start: u64, end: u64) {
+        self.received.push((start, end));
+        self.received.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.received.len());
+        for &(start, end) in &self.received {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+    }
+
+    /// Estimates how long the rest of the file will take at the current rate.
+    pub fn eta(&self) -> Option<Duration> {
+        self.throughput.eta(self.digest.size - self.received_bytes())
+    }
+
+    /// Verifies the completed file against its expected hash, moving it to its final location
+    /// if it matches and discarding it otherwise.
+    ///
+    /// If a file has appeared at the final location since the download started, the download is
+    /// saved under a de-duplicated name next to it instead of replacing it. The whole file is read,
+    /// so this should be run off the event loop.
+    pub fn finish(&mut self) -> io::Result<DownloadOutcome> {
+        let (part_path, state_path) = (self.part_path(), self.state_path());
+        let outcome = if digest_file(&part_path)?.hash == self.digest.hash {
+            if self.path.exists() {
+                self.path = unique_path(&self.path, &HashSet::new());
+            }
+            std::fs::rename(&part_path, &self.path)?;
+            DownloadOutcome::Verified
+        } else {
+            std::fs::remove_file(&part_path)?;
+            DownloadOutcome::Corrupted
+        };
+        std::fs::remove_file(state_path)?;
+        remove_if_exists(&self.ranges_path())?;
+        Ok(outcome)
+    }
+
+    /// Deletes the partial file and its sidecar files, abandoning the download.
+    pub fn discard(&self) -> io::Result<()> {
+        std::fs::remove_file(self.part_path())?;
+        std::fs::remove_file(self.state_path())?;
+        remove_if_exists(&self.ranges_path())
+    }
+
+    /// Keeps the partial file of a cancelled download, moving its state to `<hash>.part.cancelled`
+    /// in the download directory so it is not resumed at startup, only if the file is requested again.
+    pub fn keep_cancelled(&self, dir: &Path) -> io::Result<()> {
+        self.save_state()?;
+        std::fs::rename(self.state_path(), cancelled_state_path(dir, &self.digest.hash))
+    }
+
+    /// Adds a peer which has agreed to provide the file, or one which has become reachable again.
+    pub fn add_source(&mut self, peer: PeerId) -> io::Result<()> {
+        self.lost_sources.retain(|lost| *lost != peer);
+        if !self.sources.contains(&peer) {
+            self.sources.push(peer);
+        }
+        self.save_state()
+    }
+
+    /// Stops requesting chunks from a source, returning its in-flight chunks to the pending queue.
+    ///
+    /// Sources which are only unreachable are kept so they can be used again if they reappear.
+    pub fn remove_source(&mut self, peer: &PeerId, reachable_again: bool) {
+        self.sources.retain(|source| source != peer);
+        if reachable_again && !self.lost_sources.contains(peer) {
+            self.lost_sources.push(*peer);
+        }
+
+        let requeued: Vec<u64> = self.in_flight.iter()
+            .filter(|(_, (_, source))| source == peer)
+            .map(|(_, (index, _))| *index)
+            .collect();
+        self.in_flight.retain(|_, (_, source)| source != peer);
+        for index in requeued {
+            self.requeue(index);
+        }
+    }
+
+    /// Returns a chunk to the front of the pending queue, unless it is still requested from another source.
+    pub fn requeue(&mut self, index: u64) {
+        let requested = self.in_flight.values().any(|(requested, _)| *requested == index);
+        if !requested && !self.has_chunk(index) && !self.pending.contains(&index) {
+            self.pending.push_front(index);
+        }
+    }
+
+    /// Records a chunk from a source which failed verification, dropping the source once it
+    /// has sent too many. Returns true if the source was dropped.
+    pub fn record_corrupt_chunk(&mut self, index: u64, peer: &PeerId) -> bool {
+        self.requeue(index);
+        let corrupt_chunks = self.corrupt_chunks.entry(*peer).or_default();
+        *corrupt_chunks += 1;
+        if *corrupt_chunks >= MAX_CORRUPT_CHUNKS {
+            self.remove_source(peer, false);
+            return true;
+        }
+        false
+    }
+
+    /// Sends chunk requests until every source's in-flight window is full or nothing is left to request.
+    ///
+    /// Downloads are left to `schedule_downloads` while a download limit is set, so they take turns.
+    pub fn request_chunks(&mut self, swarm: &mut Swarm<Behaviour>) {
+        if SCHEDULER.lock().unwrap().is_limited(Direction::Download) {
+            return;
+        }
+        for peer in self.sources.clone() {
+            while self.request_chunk(swarm, peer) {}
+        }
+    }
+
+    /// Sends a single chunk request to a source, returning false if there was nothing to request from it.
+    pub fn request_chunk(&mut self, swarm: &mut Swarm<Behaviour>, peer: PeerId) -> bool {
+        let Some(index) = self.next_chunk(&peer) else {
+            return false;
+        };
+        if self.pending.front() == Some(&index) {
+            self.pending.pop_front();
+        }
+
+        let request_id = swarm
+            .behaviour_mut()
+            .file_transfer
+            .send_request(&peer, ChunkRequest { file_id: self.digest.hash.clone(), index });
+        self.in_flight.insert(request_id, (index, peer));
+        true
+    }
+
+    /// Chooses the next chunk to request from a source, or `None` if its in-flight window is full or
+    /// nothing is left to request.
+    ///
+    /// Once every chunk has been requested, idle sources are also given duplicate requests for chunks
+    /// still in flight elsewhere, so the end of the file is not held up by a single slow source.
+    pub fn next_chunk(&self, peer: &PeerId) -> Option<u64> {
+        let requested: Vec<u64> = self.in_flight.values()
+            .filter(|(_, source)| source == peer)
+            .map(|(index, _)| *index)
+            .collect();
+        if requested.len() >= MAX_IN_FLIGHT {
+            return None;
+        }
+
+        self.pending.front().copied().or_else(|| {
+            self.in_flight.values()
+                .map(|(index, _)| *index)
+                .filter(|index| !requested.contains(index))
+                .find(|index| self.in_flight.values().filter(|(requested, _)| requested == index).count() < 2)
+        })
+    }
+
+    // Writes the sidecar state file recording the digest, sources and received ranges, after which
+    // the ranges log is no longer needed
+    fn save_state(&self) -> io::Result<()> {
+        let state = PartialState {
+            filename: self.filename.clone(),
+            digest: self.digest.clone(),
+            sources: self.peers().iter().map(|peer| peer.to_string()).collect(),
+            path: self.path.clone(),
+            received: self.received.clone(),
+            started: self.started,
+        };
+        let bytes = serde_cbor::to_vec(&state)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        std::fs::write(self.state_path(), bytes)?;
+        remove_if_exists(&self.ranges_path())
+    }
+}
+
+/// Stops requesting chunks from a peer that is no longer reachable, pausing downloads left without a source.
+pub fn pause_downloads(downloads: &mut HashMap<String, Download>, peer: &PeerId, swarm: &mut Swarm<Behaviour>) {
+    for download in downloads.values_mut().filter(|download| download.sources.contains(peer)) {
+        logger::info!("Lost source {} for download of {:?}", peer, download.filename);
+        download.remove_source(peer, true);
+        download.request_chunks(swarm);
+
+        // Other peers may share the file, so it need not wait for this one to come back
+        if download.sources.is_empty() {
+            find_sources(download, swarm);
+        }
+    }
+}
+
+/// Looks up other providers of every download which has no source left, such as downloads restored
+/// from an earlier session.
+pub fn find_missing_sources(downloads: &HashMap<String, Download>, swarm: &mut Swarm<Behaviour>) {
+    for download in downloads.values().filter(|download| download.sources.is_empty()) {
+        find_sources(download, swarm);
+    }
+}
+
+// Asks the DHT for the peers providing a download's file, who are asked to provide it once found
+fn find_sources(download: &Download, swarm: &mut Swarm<Behaviour>) {
+    logger::info!("Looking for providers of {:?}", download.filename);
+    swarm.behaviour_mut().kademlia.get_providers(kademlia_behaviour::file_key(&download.digest.hash));
+}
+
+/// Requests chunks again from a source of any download that has become reachable again.
+pub fn resume_downloads(downloads: &mut HashMap<String, Download>, peer: &PeerId, swarm: &mut Swarm<Behaviour>) {
+    for download in downloads.values_mut().filter(|download| download.lost_sources.contains(peer)) {
+        logger::info!("Resuming download of {:?} from {}", download.filename, peer);
+        if let Err(e) = download.add_source(*peer) {
+            logger::error!("Error updating partial file {:?}: {:?}", download.filename, e);
+        }
+        download.request_chunks(swarm);
+    }
+}
+
+/// Requests chunks for downloads while a download limit is set, within the bandwidth available.
+///
+/// Each download requests one chunk from each of its sources in turn until the bandwidth runs out,
+/// starting from a different download each time, so concurrent downloads share it evenly.
+pub fn schedule_downloads(downloads: &mut HashMap<String, Download>, swarm: &mut Swarm<Behaviour>) {
+    let mut scheduler = SCHEDULER.lock().unwrap();
+    if !scheduler.is_limited(Direction::Download) {
+        // Downloads left idle when a limit was removed start requesting chunks again
+        drop(scheduler);
+        for download in downloads.values_mut().filter(|download| download.in_flight.is_empty()) {
+            download.request_chunks(swarm);
+        }
+        return;
+    }
+    if downloads.is_empty() {
+        return;
+    }
+
+    let mut hashes: Vec<String> = downloads.keys().cloned().collect();
+    hashes.sort();
+    let turn = scheduler.download_turn % hashes.len();
+    hashes.rotate_left(turn);
+    scheduler.download_turn = scheduler.download_turn.wrapping_add(1);
+
+    loop {
+        let mut requested = false;
+        for hash in &hashes {
+            let download = downloads.get_mut(hash).unwrap();
+            for peer in download.sources.clone() {
+                let Some(index) = download.next_chunk(&peer) else {
+                    continue;
+                };
+                if scheduler.try_take(Direction::Download, peer, chunk_len(download.digest.size, index)) {
+                    requested |= download.request_chunk(swarm, peer);
+                }
+            }
+        }
+        if !requested {
+            break;
+        }
+    }
+}
+
+// Returns where the state of a cancelled download whose partial file was kept is moved to
+fn cancelled_state_path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join(format!("{}.part.cancelled", hash))
+}
+
+/// Finds the partial file of a cancelled download of a file kept in a directory, so downloading
+/// the file again resumes it, moving its state back beside the partial file.
+pub fn kept_partial(dir: &Path, hash: &str) -> Option<Download> {
+    let cancelled = cancelled_state_path(dir, hash);
+    if !cancelled.exists() {
+        return None;
+    }
+    let restored = Download::from_state(&cancelled).and_then(|download| {
+        download.save_state()?;
+        std::fs::remove_file(&cancelled)?;
+        Ok(download)
+    });
+    match restored {
+        Ok(download) => Some(download),
+        Err(e) => {
+            logger::error!("Failed to restore cancelled download {:?}: {}", cancelled, e);
+            None
+        }
+    }
+}
+
+/// Loads the partially received files in a directory so they can be resumed. Downloads which were
+/// cancelled are skipped, as their state is kept under another name.
+pub fn load_partial_downloads(dir: &Path) -> HashMap<String, Download> {
+    let mut downloads = HashMap::new();
+    let mut state_paths = Vec::new();
+    if let Err(e) = collect_state_paths(dir, &mut state_paths) {
+        logger::error!("Failed to read download directory {:?}: {}", dir, e);
+        return downloads;
+    }
+
+    for path in state_paths {
+        match Download::from_state(&path) {
+            Ok(download) => {
+                logger::info!("Restored partial download of {:?}", download.filename);
+                downloads.insert(download.digest.hash.clone(), download);
+            }
+            Err(e) => logger::error!("Failed to restore partial download {:?}: {}", path, e),
+        }
+    }
+    downloads
+}
+
+// Recursively collects the state files of partial downloads below a directory, including the
+// files of bundles, which are saved in subdirectories
+fn collect_state_paths(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_state_paths(&path, paths)?;
+        } else if path.to_string_lossy().ends_with(".part.state") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Deletes a file, doing nothing if it does not exist
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Appends a suffix to the file name of a path.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Makes a file name sent by a peer safe to save, keeping only its final component and removing
+/// characters which are not allowed in file names.
+pub fn sanitise_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+        .collect();
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() {
+        "download".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Returns a path in a directory for a file name which doesn't clash with an existing file or a
+/// download in progress, adding ` (1)`, ` (2)` and so on before the extension if needed.
+pub fn download_path(dir: &Path, filename: &str) -> PathBuf {
+    unique_path(&dir.join(sanitise_filename(filename)), &HashSet::new())
+}
+
+/// Returns the path, or the first numbered variant of it, which is not taken by a file, a partial
+/// download or one of the `reserved` paths.
+pub fn unique_path(path: &Path, reserved: &HashSet<PathBuf>) -> PathBuf {
+    let is_taken = |path: &Path| reserved.contains(path) || path.exists() || with_suffix(path, ".part").exists();
+    if !is_taken(path) {
+        return path.to_path_buf();
+    }
+
+    // Number the name before its first extension, so `archive.tar.gz` becomes `archive (1).tar.gz`
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let split = name.char_indices().skip(1).find(|(_, c)| *c == '.').map_or(name.len(), |(i, _)| i);
+    let (stem, extension) = name.split_at(split);
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !is_taken(candidate))
+        .expect("Some numbered file name to be free.")
+}
+
+/// Returns the number of chunks needed to hold a file of the given size.
+pub fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE)
+}
+
+/// Returns the length of a chunk of a file, which is less than `CHUNK_SIZE` for the last chunk.
+pub fn chunk_len(size: u64, index: u64) -> u64 {
+    CHUNK_SIZE.min(size.saturating_sub(index * CHUNK_SIZE))
+}
+
+/// Returns the hex encoded SHA-256 hash of some bytes.
+pub fn hash_bytes(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Computes the digests of everything read from a reader, one chunk at a time.
+pub fn digest_reader(mut reader: impl Read) -> io::Result<FileDigest> {
+    let mut hasher = Sha256::new();
+    let mut chunk_hashes = Vec::new();
+    let mut size = 0;
+    let mut buffer = Vec::with_capacity(CHUNK_SIZE as usize);
+
+    loop {
+        buffer.clear();
+        reader.by_ref().take(CHUNK_SIZE).read_to_end(&mut buffer)?;
+        if buffer.is_empty() {
+            break;
+        }
+        hasher.update(&buffer);
+        chunk_hashes.push(hash_bytes(&buffer));
+        size += buffer.len() as u64;
+    }
+
+    Ok(FileDigest {
+        hash: hex::encode(hasher.finalize()),
+        chunk_hashes,
+        size,
+    })
+}
+
+/// Computes the digests of a file on disk.
+pub fn digest_file(path: &Path) -> io::Result<FileDigest> {
+    digest_reader(io::BufReader::new(File::open(path)?))
+}
+
+/// Reads a single chunk of a file from disk.
+pub fn read_chunk(path: &Path, index: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(index * CHUNK_SIZE))?;
+
+    let mut data = Vec::with_capacity(CHUNK_SIZE as usize);
+    file.take(CHUNK_SIZE).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Writes a single chunk of a file to disk at its offset.
+pub fn write_chunk(path: &Path, index: u64, data: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(index * CHUNK_SIZE))?;
+    file.write_all(data)?;
+    logger::debug!("Wrote chunk {} of {:?}", index, path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two whole chunks and a short last one, with each chunk's bytes different
+    fn test_data() -> Vec<u8> {
+        (0..2 * CHUNK_SIZE + 100).map(|i| (i / CHUNK_SIZE + i % 251) as u8).collect()
+    }
+
+    fn chunk(data: &[u8], index: u64) -> &[u8] {
+        let start = (index * CHUNK_SIZE) as usize;
+        &data[start..start + chunk_len(data.len() as u64, index) as usize]
+    }
+
+    // Creates a download of the test data in a fresh directory
+    fn test_download(name: &str, data: &[u8]) -> Download {
+        let dir = std::env::temp_dir().join(format!("swapbytes-transfer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let digest = digest_reader(data).unwrap();
+        Download::new("file.bin".to_string(), digest, PeerId::random(), dir.join("file.bin")).unwrap()
+    }
+
+    #[test]
+    fn digests_every_chunk() {
+        let data = test_data();
+        let digest = digest_reader(data.as_slice()).unwrap();
+        assert_eq!(digest.size, data.len() as u64);
+        assert_eq!(digest.hash, hash_bytes(&data));
+        assert_eq!(digest.chunk_hashes, (0..3).map(|index| hash_bytes(chunk(&data, index))).collect::<Vec<_>>());
+        assert_eq!(chunk_count(digest.size), 3);
+        assert_eq!(chunk_len(digest.size, 2), 100);
+    }
+
+    #[test]
+    fn verifies_chunks_against_their_digests() {
+        let data = test_data();
+        let download = test_download("verify", &data);
+        assert!(download.verify_chunk(0, chunk(&data, 0)));
+        assert!(download.verify_chunk(2, chunk(&data, 2)));
+
+        let mut altered = chunk(&data, 1).to_vec();
+        altered[10] ^= 1;
+        assert!(!download.verify_chunk(1, &altered));
+        assert!(!download.verify_chunk(1, chunk(&data, 0)));
+        assert!(!download.verify_chunk(1, &chunk(&data, 1)[1..]));
+        assert!(!download.verify_chunk(3, chunk(&data, 2)));
+    }
+
+    #[test]
+    fn resumes_and_verifies_a_download() {
+        let data = test_data();
+        let mut download = test_download("resume", &data);
+        download.write_chunk(2, chunk(&data, 2)).unwrap();
+        download.write_chunk(0, chunk(&data, 0)).unwrap();
+
+        let mut download = Download::from_state(&download.state_path()).unwrap();
+        assert_eq!(download.received, [(0, CHUNK_SIZE), (2 * CHUNK_SIZE, data.len() as u64)]);
+        assert_eq!(download.pending, [1]);
+
+        download.write_chunk(1, chunk(&data, 1)).unwrap();
+        assert!(download.is_complete());
+        assert_eq!(download.finish().unwrap(), DownloadOutcome::Verified);
+        assert_eq!(std::fs::read(&download.path).unwrap(), data);
+    }
+
+    #[test]
+    fn discards_a_corrupted_download() {
+        let data = test_data();
+        let mut download = test_download("corrupted", &data);
+        for index in 0..3 {
+            download.write_chunk(index, chunk(&data, (index + 1) % 3)).unwrap();
+        }
+        assert_eq!(download.finish().unwrap(), DownloadOutcome::Corrupted);
+        assert!(!download.path.exists() && !download.part_path().exists());
+    }
+
+    #[test]
+    fn sanitises_file_names() {
+        assert_eq!(sanitise_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitise_filename("C:\\Users\\me\\notes.txt"), "notes.txt");
+        assert_eq!(sanitise_filename("what?<now>.txt"), "whatnow.txt");
+        assert_eq!(sanitise_filename(".."), "download");
+        assert_eq!(sanitise_filename(" \u{7}"), "download");
+    }
+}
@@ -0,0 +1,162 @@
+use glob::{MatchOptions, Pattern};
+use libp2p::PeerId;
+use std::path::{Component, Path};
+use std::str::FromStr;
+use crate::ledger::Balance;
+
+/// A rule which approves file requests without asking the user, when every condition it sets matches.
+///
+/// Rules are written as comma separated conditions, for example `peer=<peer id>,path=builds/**,max-size=100MB`.
+/// Peers are matched by peer ID only, as anyone can claim any username in the DHT.
+#[derive(Debug, Clone, Default)]
+pub struct AcceptRule {
+    /// Peer the request must come from
+    pub peer: Option<PeerId>,
+    /// Glob the requested path within the share directory must match
+    pub path: Option<Pattern>,
+    /// Largest file size the rule approves, in bytes
+    pub max_size: Option<u64>,
+}
+
+/// Details of an incoming file request which rules are checked against.
+pub struct PolicyRequest<'a> {
+    pub peer: PeerId,
+    pub path: &'a str,
+    /// Size of the requested file, if it could be found
+    pub size: Option<u64>,
+}
+
+impl AcceptRule {
+    /// Returns true if the request meets every condition of the rule.
+    pub fn matches(&self, request: &PolicyRequest) -> bool {
+        // Match globs against the path with `.` components removed, so `*` only matches within a directory
+        let path = Path::new(request.path)
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+
+        self.peer.is_none_or(|peer| peer == request.peer)
+            && self.path.as_ref().is_none_or(|pattern| pattern.matches_with(&path, options))
+            && self.max_size.is_none_or(|max_size| request.size.is_some_and(|size| size <= max_size))
+    }
+}
+
+impl FromStr for AcceptRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let mut accept_rule = AcceptRule::default();
+        for condition in rule.split(',').map(str::trim).filter(|condition| !condition.is_empty()) {
+            let (key, value) = condition.split_once('=')
+                .ok_or_else(|| format!("expected key=value, found {:?}", condition))?;
+            match key.trim() {
+                "peer" => accept_rule.peer = Some(value.parse().map_err(|e| format!("invalid peer ID {:?}: {}", value, e))?),
+                "user" => return Err("usernames can be claimed by any peer, match peers with peer=<peer id> instead".to_string()),
+                "path" => accept_rule.path = Some(Pattern::new(value).map_err(|e| format!("invalid glob {:?}: {}", value, e))?),
+                "max-size" => accept_rule.max_size = Some(parse_size(value)?),
+                key => return Err(format!("unknown condition {:?}, expected peer, path or max-size", key)),
+            }
+        }
+
+        // A rule without conditions would approve everything, which has to be asked for explicitly with `path=**`
+        if accept_rule.peer.is_none() && accept_rule.path.is_none() && accept_rule.max_size.is_none() {
+            return Err("a rule needs at least one condition".to_string());
+        }
+        Ok(accept_rule)
+    }
+}
+
+/// Rules deciding which incoming file requests are approved automatically.
+#[derive(Debug, Clone, Default)]
+pub struct AcceptPolicy {
+    pub rules: Vec<AcceptRule>,
+}
+
+impl AcceptPolicy {
+    /// Returns true if any rule approves the request, otherwise it is left for the user to answer.
+    pub fn approves(&self, request: &PolicyRequest) -> bool {
+        self.rules.iter().any(|rule| rule.matches(request))
+    }
+}
+
+/// What happens to requests from peers whose swap ratio is below the minimum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RatioAction {
+    /// Always ask before accepting their requests, and serve their chunks after everyone else's
+    #[default]
+    Deprioritise,
+    /// Refuse their requests
+    Refuse,
+}
+
+/// Policy for peers who take much more than they give.
+#[derive(Debug, Clone, Default)]
+pub struct RatioPolicy {
+    /// Smallest ratio of bytes received from a peer to bytes sent to it, or `None` to treat every peer alike
+    pub min_ratio: Option<f64>,
+    pub action: RatioAction,
+    /// Bytes a peer may take before its ratio is checked, so new peers can get started
+    pub grace: u64,
+}
+
+impl RatioPolicy {
+    /// Returns true if a peer has taken more than the grace allowance and given too little back.
+    pub fn is_below(&self, balance: &Balance) -> bool {
+        match (self.min_ratio, balance.ratio()) {
+            (Some(min_ratio), Some(ratio)) => balance.uploaded > self.grace && ratio < min_ratio,
+            _ => false,
+        }
+    }
+}
+
+/// Parses a size such as `512`, `64KB` or `1.5GB`, using 1024 byte units like the rest of the UI.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        "T" | "TB" => 1024 * 1024 * 1024 * 1024,
+        unit => return Err(format!("unknown size unit {:?}", unit)),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("invalid size {:?}", size))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("2KB"), Ok(2048));
+        assert_eq!(parse_size("1.5mb"), Ok(3 * 512 * 1024));
+        assert_eq!(parse_size(" 2 GB "), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("2PB").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("MB").is_err());
+    }
+
+    #[test]
+    fn matches_rules_by_peer_id() {
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let rule: AcceptRule = format!("peer={},path=builds/**,max-size=1KB", peer).parse().unwrap();
+        let request = |peer, path, size| PolicyRequest { peer, path, size: Some(size) };
+        assert!(rule.matches(&request(peer, "./builds/app.zip", 1024)));
+        assert!(!rule.matches(&request(other, "builds/app.zip", 1024)));
+        assert!(!rule.matches(&request(peer, "notes.txt", 1024)));
+        assert!(!rule.matches(&request(peer, "builds/app.zip", 1025)));
+
+        assert!("user=alice".parse::<AcceptRule>().is_err());
+        assert!("".parse::<AcceptRule>().is_err());
+    }
+}
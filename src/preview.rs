@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Number of lines of a text file sent in a preview when the requester asks for more.
+pub const MAX_PREVIEW_LINES: usize = 100;
+
+/// Number of lines of a text file requested for a preview by default.
+pub const DEFAULT_PREVIEW_LINES: usize = 20;
+
+/// Bytes read from the start of a file to guess its type and find its first lines.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Longest line sent in a text preview, in characters.
+const MAX_LINE_LENGTH: usize = 200;
+
+/// Largest image decoded for a preview, in bytes, as the whole image has to be read.
+const MAX_IMAGE_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Largest image preview sent, in pixels, each shown as half of a character cell.
+const IMAGE_PREVIEW_WIDTH: u32 = 64;
+const IMAGE_PREVIEW_HEIGHT: u32 = 48;
+
+/// What a preview shows of a file's contents.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PreviewContent {
+    /// The first lines of a text file
+    Text(Vec<String>),
+    /// A scaled down copy of an image, as rows of RGB pixels
+    Image {
+        width: u32,
+        height: u32,
+        pixels: Vec<[u8; 3]>,
+    },
+    /// Only the metadata can be shown
+    None,
+}
+
+/// A small preview of a shared file, sent so a peer can check it before downloading it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilePreview {
+    pub filename: String,
+    pub size: u64,
+    /// MIME type guessed from the first bytes of the file
+    pub mime: String,
+    pub content: PreviewContent,
+}
+
+impl FilePreview {
+    /// Builds a preview of a file with up to `lines` lines of text, or a low resolution copy of an image.
+    pub fn build(path: &Path, filename: String, lines: usize) -> io::Result<Self> {
+        let size = std::fs::metadata(path)?.len();
+        let mut sample = Vec::new();
+        File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+        let mime = guess_mime(&sample);
+
+        let content = if mime == "text/plain" {
+            PreviewContent::Text(text_lines(&sample, lines.min(MAX_PREVIEW_LINES)))
+        } else if mime.starts_with("image/") && size <= MAX_IMAGE_SIZE {
+            image_preview(path).unwrap_or(PreviewContent::None)
+        } else {
+            PreviewContent::None
+        };
+        Ok(Self { filename, size, mime: mime.to_string(), content })
+    }
+
+    /// Returns true if a preview received from a peer is within the limits previews are built
+    /// with, so it can be shown safely.
+    pub fn is_valid(&self) -> bool {
+        match &self.content {
+            PreviewContent::Text(lines) => {
+                lines.len() <= MAX_PREVIEW_LINES && lines.iter().all(|line| line.chars().count() <= MAX_LINE_LENGTH)
+            }
+            PreviewContent::Image { width, height, pixels } => {
+                (1..=IMAGE_PREVIEW_WIDTH).contains(width)
+                    && (1..=IMAGE_PREVIEW_HEIGHT).contains(height)
+                    && pixels.len() == (*width as usize) * (*height as usize)
+            }
+            PreviewContent::None => true,
+        }
+    }
+}
+
+/// Guesses the MIME type of a file from its first bytes, treating valid UTF-8 without control
+/// characters as plain text.
+pub fn guess_mime(bytes: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"BM", "image/bmp"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"BZh", "application/x-bzip2"),
+        (b"\xfd7zXZ\x00", "application/x-xz"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"Rar!\x1a\x07", "application/vnd.rar"),
+        (b"\x7fELF", "application/x-elf"),
+        (b"MZ", "application/x-msdownload"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"SQLite format 3\x00", "application/vnd.sqlite3"),
+    ];
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(signature, _)| bytes.starts_with(signature)) {
+        return mime;
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" {
+        match &bytes[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            b"AVI " => return "video/x-msvideo",
+            _ => {}
+        }
+    }
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+
+    // A multi-byte character may be cut off at the end of the sample
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return "application/octet-stream",
+    };
+    if text.chars().all(|c| !c.is_control() || c.is_whitespace()) {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+// Takes the first lines of a sample of text, shortening any which are too long to show
+fn text_lines(sample: &[u8], lines: usize) -> Vec<String> {
+    String::from_utf8_lossy(sample)
+        .lines()
+        .take(lines)
+        .map(|line| line.chars().take(MAX_LINE_LENGTH).collect())
+        .collect()
+}
+
+// Decodes an image and scales it down to fit the preview, keeping its aspect ratio
+fn image_preview(path: &Path) -> Option<PreviewContent> {
+    let image = image::ImageReader::open(path).ok()?.with_guessed_format().ok()?.decode().ok()?;
+    let thumbnail = image.thumbnail(IMAGE_PREVIEW_WIDTH, IMAGE_PREVIEW_HEIGHT).to_rgb8();
+    Some(PreviewContent::Image {
+        width: thumbnail.width(),
+        height: thumbnail.height(),
+        pixels: thumbnail.pixels().map(|pixel| pixel.0).collect(),
+    })
+}
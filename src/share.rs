@@ -0,0 +1,322 @@
+use lazy_static::lazy_static;
+use libp2p::{kad, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::logger;
+use crate::network::transfer::{self, FileDigest};
+
+/// Reasons a requested path cannot be served from the share directory.
+#[derive(Debug)]
+pub enum ShareError {
+    /// The path points outside the share directory, or at something other than a file
+    Denied(String),
+    /// The path could not be found or read
+    NotFound(io::Error),
+}
+
+impl std::fmt::Display for ShareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShareError::Denied(reason) => write!(f, "denied: {}", reason),
+            ShareError::NotFound(e) => write!(f, "not found: {}", e),
+        }
+    }
+}
+
+/// Resolves a path requested by a peer to a file inside the share directory.
+///
+/// The request must be a relative path without `..` components, and once symlinks are
+/// resolved the file must still be inside the share directory.
+pub fn resolve(share_dir: &Path, requested: &str) -> Result<PathBuf, ShareError> {
+    let resolved = resolve_path(share_dir, requested)?;
+    if !resolved.is_file() {
+        return Err(ShareError::Denied(format!("{:?} is not a file", requested)));
+    }
+    Ok(resolved)
+}
+
+/// Resolves the paths requested as one bundle to the files they contain, recursing into directories.
+///
+/// Each file is returned with its path in the bundle, which starts with the name of the requested
+/// file or directory it was found in.
+pub fn resolve_bundle(share_dir: &Path, requested: &[String]) -> Result<Vec<(String, PathBuf)>, ShareError> {
+    let mut files = Vec::new();
+    for request in requested {
+        let resolved = resolve_path(share_dir, request)?;
+        let name = Path::new(request).file_name()
+            .or_else(|| resolved.file_name())
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if resolved.is_file() {
+            files.push((name, resolved));
+            continue;
+        }
+
+        let mut names = Vec::new();
+        collect_names(&resolved, "", &mut names).map_err(ShareError::NotFound)?;
+        names.sort();
+        for relative in names {
+            // Apply the same rules as single requests, so symlinks leading out of the share directory are skipped
+            match resolve(share_dir, &format!("{}/{}", request.trim_end_matches('/'), relative)) {
+                Ok(path) => files.push((format!("{}/{}", name, relative), path)),
+                Err(e) => logger::info!("Not sending {:?}: {}", relative, e),
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the path of a resolved file relative to the share directory, using `/` separators, which
+/// is the same for every alias of the file a peer could request it by.
+pub fn shared_name(share_dir: &Path, resolved: &Path) -> String {
+    let root = share_dir.canonicalize().unwrap_or_default();
+    let relative = resolved.strip_prefix(&root).unwrap_or(resolved);
+    relative.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Resolves a requested path to a file or directory inside the share directory.
+fn resolve_path(share_dir: &Path, requested: &str) -> Result<PathBuf, ShareError> {
+    let requested_path = Path::new(requested);
+    if requested.is_empty() || !requested_path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(ShareError::Denied(format!("{:?} is not a relative path inside the share directory", requested)));
+    }
+
+    let root = share_dir.canonicalize().map_err(ShareError::NotFound)?;
+    let resolved = root.join(requested_path).canonicalize().map_err(ShareError::NotFound)?;
+
+    if !resolved.starts_with(&root) {
+        return Err(ShareError::Denied(format!("{:?} resolves outside the share directory", requested)));
+    }
+    Ok(resolved)
+}
+
+/// Details of a file in the share directory, as listed to other peers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedFileInfo {
+    /// Path relative to the share directory, using `/` separators
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+    /// Modification time in seconds since the Unix epoch
+    pub modified: u64,
+}
+
+/// Caches the digests of shared files so they are only hashed again once they change.
+#[derive(Default)]
+pub struct DigestCache {
+    entries: HashMap<PathBuf, (SystemTime, FileDigest)>,
+}
+
+impl DigestCache {
+    // Returns the cached digest of a file, if it has not changed since it was hashed
+    fn get(&self, path: &Path, modified: SystemTime, size: u64) -> Option<FileDigest> {
+        self.entries.get(path)
+            .filter(|(cached_modified, digest)| *cached_modified == modified && digest.size == size)
+            .map(|(_, digest)| digest.clone())
+    }
+}
+
+/// Returns the digest of a file, hashing it only if it has changed since it was last hashed.
+///
+/// The cache is not locked while the file is hashed, so looking up other files is not held up.
+pub fn digest(path: &Path) -> io::Result<FileDigest> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata.modified()?;
+    if let Some(digest) = DIGEST_CACHE.lock().unwrap().get(path, modified, metadata.len()) {
+        return Ok(digest);
+    }
+
+    let digest = transfer::digest_file(path)?;
+    DIGEST_CACHE.lock().unwrap().entries.insert(path.to_path_buf(), (modified, digest.clone()));
+    Ok(digest)
+}
+
+/// Splits a file name into the normalised keywords it can be searched by.
+///
+/// Keywords are lowercase runs of letters and digits, at least two characters long.
+pub fn keywords(name: &str) -> Vec<String> {
+    let mut keywords: Vec<String> = name
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 2)
+        .map(str::to_string)
+        .collect();
+    keywords.sort();
+    keywords.dedup();
+    keywords
+}
+
+/// A file matching a search, and the peer sharing it.
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub peer: PeerId,
+    pub file: SharedFileInfo,
+}
+
+/// A network-wide search for shared files.
+#[derive(Debug, Clone, Default)]
+pub struct Search {
+    /// Normalised search terms, which must all match a file
+    pub terms: Vec<String>,
+    /// Peers found to provide a matching keyword or hash, whose file lists have been requested
+    pub providers: Vec<PeerId>,
+    /// Provider lookups started for this search, so lookups made for other reasons are not counted
+    pub queries: HashSet<kad::QueryId>,
+    /// Matching files found so far
+    pub results: Vec<SearchResult>,
+}
+
+impl Search {
+    /// Starts a search for files matching every one of the given terms.
+    pub fn new(query: &str) -> Self {
+        Self {
+            terms: query.split_whitespace().map(str::to_lowercase).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if a file's name contains every term, or its hash equals one of them.
+    pub fn matches(&self, file: &SharedFileInfo) -> bool {
+        let name = file.name.to_lowercase();
+        self.terms.contains(&file.hash)
+            || self.terms.iter().all(|term| name.contains(term.as_str()))
+    }
+
+    /// Adds the matching files from a provider's file list to the results.
+    pub fn add_results(&mut self, peer: PeerId, files: &[SharedFileInfo]) {
+        let matching: Vec<SearchResult> = files.iter()
+            .filter(|file| self.matches(file))
+            .map(|file| SearchResult { peer, file: file.clone() })
+            .collect();
+        self.results.retain(|result| result.peer != peer);
+        self.results.extend(matching);
+    }
+}
+
+lazy_static! {
+    // Digests of shared files, kept separately from the application state as hashing can be slow
+    pub static ref DIGEST_CACHE: Mutex<DigestCache> = Mutex::new(DigestCache::default());
+
+    // Files in the share directory as last catalogued in the background, which requests from
+    // peers are answered from so they never wait for the share directory to be hashed
+    pub static ref CATALOGUE: Mutex<Vec<SharedFileInfo>> = Mutex::new(Vec::new());
+}
+
+/// Lists every file peers may request from the share directory, with its size, hash and modification time.
+///
+/// Files which have changed are hashed again, so this can take a while and is run in the background.
+pub fn catalogue(share_dir: &Path) -> io::Result<Vec<SharedFileInfo>> {
+    let mut names = Vec::new();
+    collect_names(share_dir, "", &mut names)?;
+    names.sort();
+
+    let mut files = Vec::new();
+    for name in names {
+        // Apply the same rules as requests, so symlinks leading out of the share directory are not listed
+        let path = match resolve(share_dir, &name) {
+            Ok(path) => path,
+            Err(e) => {
+                logger::info!("Not listing {:?}: {}", name, e);
+                continue;
+            }
+        };
+        // A file which cannot be read, such as one removed since the directory was listed, is left
+        // out rather than failing the whole scan
+        let read = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .and_then(|modified| Ok((modified, digest(&path)?)));
+        let (modified, digest) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                logger::error!("Not listing {:?}: {}", name, e);
+                continue;
+            }
+        };
+        let modified = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        files.push(SharedFileInfo { name, size: digest.size, hash: digest.hash, modified });
+    }
+    Ok(files)
+}
+
+// Recursively collects the relative paths of files below a directory, without following symlinked directories.
+fn collect_names(dir: &Path, prefix: &str, names: &mut Vec<String>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_names(&entry.path(), &format!("{}/", name), names)?;
+        } else if file_type.is_file() || file_type.is_symlink() {
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates a share directory holding `a.txt` and `sub/b.txt`, next to a file outside it
+    fn share_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("swapbytes-share-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("share/sub")).unwrap();
+        std::fs::write(root.join("share/a.txt"), "a").unwrap();
+        std::fs::write(root.join("share/sub/b.txt"), "b").unwrap();
+        std::fs::write(root.join("secret.txt"), "secret").unwrap();
+        root.join("share")
+    }
+
+    #[test]
+    fn resolves_files_inside_the_share_directory() {
+        let share = share_dir("inside");
+        let root = share.canonicalize().unwrap();
+        assert_eq!(resolve(&share, "a.txt").unwrap(), root.join("a.txt"));
+        assert_eq!(resolve(&share, "./sub/b.txt").unwrap(), root.join("sub/b.txt"));
+        assert_eq!(shared_name(&share, &resolve(&share, "./sub/./b.txt").unwrap()), "sub/b.txt");
+    }
+
+    #[test]
+    fn refuses_paths_leading_outside_the_share_directory() {
+        let share = share_dir("outside");
+        let secret = share.parent().unwrap().join("secret.txt");
+        for requested in ["", "../secret.txt", "sub/../../secret.txt", "sub/../a.txt", secret.to_str().unwrap()] {
+            assert!(matches!(resolve(&share, requested), Err(ShareError::Denied(_))), "{:?} was not denied", requested);
+        }
+    }
+
+    #[test]
+    fn refuses_directories_as_files() {
+        let share = share_dir("directory");
+        assert!(matches!(resolve(&share, "sub"), Err(ShareError::Denied(_))));
+        assert!(matches!(resolve(&share, "missing.txt"), Err(ShareError::NotFound(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_leading_outside_the_share_directory() {
+        let share = share_dir("symlink");
+        std::os::unix::fs::symlink(share.parent().unwrap().join("secret.txt"), share.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(share.parent().unwrap(), share.join("parent")).unwrap();
+        std::os::unix::fs::symlink(share.join("a.txt"), share.join("inside.txt")).unwrap();
+
+        assert!(matches!(resolve(&share, "link.txt"), Err(ShareError::Denied(_))));
+        assert!(matches!(resolve(&share, "parent/secret.txt"), Err(ShareError::Denied(_))));
+        assert_eq!(shared_name(&share, &resolve(&share, "inside.txt").unwrap()), "a.txt");
+
+        let listed: Vec<String> = catalogue(&share).unwrap().into_iter().map(|file| file.name).collect();
+        assert_eq!(listed, ["a.txt", "inside.txt", "sub/b.txt"]);
+    }
+}
@@ -1,19 +1,34 @@
 use lazy_static::lazy_static;
 use std::sync::{Mutex, Arc};
 use ratatui::widgets::ListState;
-use libp2p::PeerId;
-use std::collections::HashMap;
-use crate::network::network::{Response, Client};
-use libp2p_request_response::ResponseChannel;
+use libp2p::{kad, PeerId};
+use libp2p::identity::Keypair;
+use std::collections::{HashMap, HashSet};
+use crate::network::network::Response;
+use crate::network::bundle::Bundle;
+use crate::network::compression::Compression;
+use crate::network::transfer::{Download, DownloadOutcome, FileDigest, FinishedDownload, Upload};
+use std::path::{Path, PathBuf};
+use libp2p_request_response::{OutboundRequestId, ResponseChannel};
+use std::time::Instant;
 use crate::logger;
-use std::sync::atomic::{AtomicBool, Ordering};
+use crate::share::{Search, SharedFileInfo};
+use crate::policy::{AcceptPolicy, RatioPolicy};
+use crate::ledger::Ledger;
+use crate::chat::{ChatMessage, DeliveryState, HistoryRequest};
+use crate::chat_log::ChatLog;
+use crate::preview::FilePreview;
+use crate::history::{self, History, HistoryEntry, HistoryPeer, TransferDirection, TransferOutcome};
 
 #[derive(Clone, PartialEq, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Screen {
     LoginScreen,
     MainScreen,
     SelectRoomScreen,
     DMScreen,
+    TransfersScreen,
+    HistoryScreen,
 }
 
 /// App holds the state of the application
@@ -23,9 +38,9 @@ pub struct App {
     /// Position of cursor in the editor area
     pub character_index: usize,
     /// History of recorded messages for public rooms
-    pub public_messages: HashMap<String, Vec<String>>,
+    pub public_messages: HashMap<String, Vec<ChatMessage>>,
     /// History of recorded messages for private conversations
-    pub private_messages: HashMap<String, Vec<String>>,
+    pub private_messages: HashMap<String, Vec<ChatMessage>>,
     /// Currently displayed screen
     pub current_screen: Screen,
     /// Username of the current user
@@ -44,12 +59,76 @@ pub struct App {
     pub usernames: HashMap<String, String>,
     /// List of peers without usernames
     pub peers_no_username: Vec<PeerId>,
-    /// Flag indicating if usernames are being updated
-    updating_usernames: AtomicBool,
     /// ID of the current peer
     pub my_peer_id: Option<PeerId>,
     /// List of current requests
     pub current_requests: Vec<RequestItem>,
+    /// Rules for accepting incoming file requests without asking
+    pub accept_policy: AcceptPolicy,
+    /// File requests sent to other peers, with whether they were accepted
+    pub sent_requests: Vec<SentRequest>,
+    /// Directory whose files peers may request
+    pub share_dir: PathBuf,
+    /// Directory received files are saved to
+    pub download_dir: PathBuf,
+    /// Largest file or bundle sent to peers, in bytes
+    pub max_file_size: Option<u64>,
+    /// Whether downloads cancelled by their providers keep their partial files
+    pub keep_partials: bool,
+    /// Whether chat messages show how long ago they were sent rather than when
+    pub relative_times: bool,
+    /// Every message sent or received, including those from earlier sessions
+    pub chat_log: ChatLog,
+    /// Key of this peer, which its messages are signed with
+    pub keypair: Option<Keypair>,
+    /// Most messages asked for when syncing a room's history from another member
+    pub sync_limit: usize,
+    /// Oldest message asked for when syncing a room's history, in seconds
+    pub sync_age: u64,
+    /// History requests sent to other members of rooms and not answered yet
+    pub history_requests: HashMap<OutboundRequestId, HistoryRequest>,
+    /// Direct messages which could not be sent yet, by conversation topic
+    pub outbox: HashMap<String, Vec<ChatMessage>>,
+    /// File the outbox is kept in, so queued messages are still sent after a restart
+    pub outbox_file: Option<PathBuf>,
+    /// Delivery state of the direct messages sent this session, by message ID
+    pub delivery: HashMap<String, DeliveryState>,
+    /// Whether undelivered direct messages are also stored in the DHT for the other peer to pick up
+    pub park_messages: bool,
+    /// Peers found this session which have since gone, who can still be sent direct messages
+    pub away_peers: Vec<PeerId>,
+    /// Every transfer which has finished, including those from earlier sessions
+    pub history: History,
+    /// Bytes swapped with each peer
+    pub ledger: Ledger,
+    /// How peers who give back too little are treated
+    pub ratio_policy: RatioPolicy,
+    /// Preview of a file shared by a peer, shown until it is closed
+    pub preview: Option<(PeerId, FilePreview)>,
+    /// Search terms filtering the history tab
+    pub history_query: String,
+    /// Selected entry in the history tab
+    pub history_state: ListState,
+    /// Files shared by each peer, as last listed by them
+    pub catalogues: HashMap<PeerId, Vec<SharedFileInfo>>,
+    /// Network-wide file search in progress, if any
+    pub search: Option<Search>,
+    /// Files offered to peers, keyed by the hash used in chunk requests
+    pub shared_files: HashMap<String, PathBuf>,
+    /// Peers allowed to request chunks of each shared file, by hash
+    pub grants: HashSet<(PeerId, String)>,
+    /// Downloads in progress, keyed by the hash of the file
+    pub downloads: HashMap<String, Download>,
+    /// Peers already asked to provide a download by each provider lookup still running
+    pub source_queries: HashMap<kad::QueryId, HashSet<PeerId>>,
+    /// Bundles of files being downloaded, keyed by the hash of their manifest
+    pub bundles: HashMap<String, Bundle>,
+    /// Downloads which have completed, with the result of verifying them
+    pub finished_downloads: Vec<FinishedDownload>,
+    /// Files being served to peers which have been allowed to request them
+    pub uploads: Vec<Upload>,
+    /// State for managing the transfer list selection
+    pub transfer_state: ListState,
 }
 
 impl App {
@@ -85,9 +164,41 @@ impl App {
             peers: Vec::new(),
             usernames: HashMap::new(),
             peers_no_username: Vec::new(),
-            updating_usernames: AtomicBool::new(false), // Initialize to false
             my_peer_id: None,
             current_requests: Vec::new(),
+            accept_policy: AcceptPolicy::default(),
+            sent_requests: Vec::new(),
+            share_dir: PathBuf::from("shared"),
+            download_dir: PathBuf::from("downloads"),
+            max_file_size: None,
+            keep_partials: false,
+            relative_times: false,
+            chat_log: ChatLog::default(),
+            keypair: None,
+            sync_limit: 100,
+            sync_age: 24 * 60 * 60,
+            history_requests: HashMap::new(),
+            source_queries: HashMap::new(),
+            outbox: HashMap::new(),
+            outbox_file: None,
+            delivery: HashMap::new(),
+            park_messages: false,
+            away_peers: Vec::new(),
+            history: History::default(),
+            ledger: Ledger::default(),
+            ratio_policy: RatioPolicy::default(),
+            preview: None,
+            history_query: String::new(),
+            history_state: ListState::default(),
+            catalogues: HashMap::new(),
+            search: None,
+            shared_files: HashMap::new(),
+            grants: HashSet::new(),
+            downloads: HashMap::new(),
+            bundles: HashMap::new(),
+            finished_downloads: Vec::new(),
+            uploads: Vec::new(),
+            transfer_state: ListState::default(),
         }
     }
 
@@ -146,30 +257,83 @@ impl App {
         self.character_index = 0;
     }
 
-    // Submits a public message to the current room
-    pub fn submit_public_room_message(&mut self) {
-        let final_msg = format!("{}: {}", self.username.clone(), self.input.clone());
-        // Determine the current room
-        if let Some(current_room_name) = self.rooms.get(self.current_room) {
-            // Push the message to the appropriate room's message vector
-            self.public_messages.entry(current_room_name.clone())
-                .or_insert_with(Vec::new)
-                .push(final_msg.clone());
-        }
+    // Submits a public message to the current room, returning it with the room's name so it can be published
+    pub fn submit_public_room_message(&mut self) -> Option<(ChatMessage, String)> {
+        let room = self.rooms.get(self.current_room).cloned().unwrap_or_else(|| "global".to_string());
+        let message = ChatMessage::from_input(self.keypair.as_ref()?, &room, &self.input);
+        self.add_message(&room, message.clone());
 
         self.input.clear();
         self.reset_cursor();
+        Some((message, room))
     }
 
-    // Submits a private message to a specific topic
-    pub fn submit_private_message(&mut self, topic: String) {
-        let final_msg = format!("{}: {}", self.username.clone(), self.input.clone());
-        // Push the message to the appropriate topic's message vector
-        self.private_messages.entry(topic.clone())
-            .or_insert_with(Vec::new)
-            .push(final_msg.clone());
+    // Submits a private message to a specific topic, returning it so it can be published
+    pub fn submit_private_message(&mut self, topic: String) -> Option<ChatMessage> {
+        let message = ChatMessage::from_input(self.keypair.as_ref()?, &topic, &self.input);
+        self.add_message(&topic, message.clone());
         self.input.clear();
         self.reset_cursor();
+        Some(message)
+    }
+
+    // Adds a message to a room, or to a direct conversation if the topic is too long to be a room
+    // name, and records it in the chat log. Messages already in the conversation are ignored, as
+    // they can arrive both live and when syncing history
+    pub fn add_message(&mut self, topic: &str, message: ChatMessage) -> bool {
+        let messages = if topic.len() <= 64 { &mut self.public_messages } else { &mut self.private_messages };
+        let messages = messages.entry(topic.to_string()).or_default();
+        if messages.iter().any(|existing| existing.id == message.id) {
+            return false;
+        }
+        self.chat_log.record(topic, &message);
+        messages.push(message);
+        true
+    }
+
+    // Drops the messages of rooms and conversations which are past their retention
+    pub fn prune_messages(&mut self) {
+        self.chat_log.prune(&mut self.public_messages, &mut self.private_messages);
+    }
+
+    // Merges messages from a room's history, or picked up for a direct conversation, into it,
+    // skipping those already there, and orders its messages by when they were sent. Returns how
+    // many messages were new
+    pub fn merge_messages(&mut self, topic: &str, messages: Vec<ChatMessage>) -> usize {
+        let added = messages.into_iter().filter(|message| self.add_message(topic, message.clone())).count();
+        if added > 0 {
+            let messages = if topic.len() <= 64 { &mut self.public_messages } else { &mut self.private_messages };
+            if let Some(messages) = messages.get_mut(topic) {
+                messages.sort_by_key(|message| message.timestamp);
+            }
+        }
+        added
+    }
+
+    // Asks for the messages of a room sent within the last `sync_age` seconds, up to `sync_limit`
+    pub fn history_request(&self, room: &str) -> HistoryRequest {
+        let since = history::unix_now().saturating_sub(self.sync_age).saturating_mul(1000);
+        HistoryRequest { room: room.to_string(), since, limit: self.sync_limit }
+    }
+
+    // Returns the last `limit` messages of a room sent since a time in milliseconds, oldest first
+    pub fn recent_messages(&self, room: &str, since: u64, limit: usize) -> Vec<ChatMessage> {
+        let Some(messages) = self.public_messages.get(room) else {
+            return Vec::new();
+        };
+        let recent: Vec<&ChatMessage> = messages.iter().filter(|message| message.timestamp >= since).collect();
+        recent[recent.len().saturating_sub(limit)..].iter().map(|message| (*message).clone()).collect()
+    }
+
+    // Returns the name to show for the sender of a message, which is the username the peer
+    // published, or the end of its peer ID if it has none
+    pub fn sender_name(&self, sender: &str) -> String {
+        if self.my_peer_id.is_some_and(|peer_id| peer_id.to_string() == sender) {
+            return self.username.clone();
+        }
+        self.usernames.get(sender)
+            .cloned()
+            .unwrap_or_else(|| sender[sender.len().saturating_sub(8)..].to_string())
     }
 
     // Clears the input field
@@ -178,13 +342,13 @@ impl App {
         self.reset_cursor();
     }
 
-    // Updates the list of usernames based on connected peers
-    pub async fn update_usernames(&mut self, client: &mut Client) {
-        if self.updating_usernames.load(Ordering::SeqCst) {
-            return; // An update is already in progress
-        }
+    // Updates the list of usernames based on connected peers.
+    //
+    // Drops usernames of peers that have left and returns the peer IDs whose usernames
+    // still need to be fetched, so the caller can query them without holding the lock.
+    pub fn update_usernames(&mut self) -> Vec<String> {
         logger::info!("{:?}, {:?}, {:?}", self.usernames.len(), self.peers.len(), self.peers_no_username.len());
-        self.updating_usernames.store(true, Ordering::SeqCst);
+        let mut missing = Vec::new();
 
         if self.usernames.len() > (self.peers.len() - self.peers_no_username.len()) {
             let mut new_usernames = HashMap::new();
@@ -195,33 +359,393 @@ impl App {
                 }
             }
             self.usernames = new_usernames;
-        } else if self.usernames.len() < (self.peers.len() - self.peers_no_username.len()) {
+        } else if self.usernames.len() < (self.peers.len() - self.peers_no_username.len())
+            || !self.peers_no_username.is_empty()
+        {
             for peer in &self.peers {
                 let peer_to_string = peer.to_string();
                 if !self.usernames.contains_key(&peer_to_string) {
-                    client.get_username(peer_to_string).await;
+                    missing.push(peer_to_string);
                 }
             }
-        } else if self.peers_no_username.len() > 0 {
-            for peer in &self.peers {
-                let peer_to_string = peer.to_string();
-                if !self.usernames.contains_key(&peer_to_string) {
-                    client.get_username(peer_to_string).await;
+        }
+        logger::info!("{:?}", self.usernames);
+        missing
+    }
+
+    // Verifies a completed download in the background, as the whole file has to be hashed, then
+    // records its outcome for display
+    pub fn finish_download(&mut self, download: Download) {
+        logger::info!("Verifying {:?}", download.filename);
+        tokio::spawn(async move {
+            let verified = tokio::task::spawn_blocking(move || {
+                let mut download = download;
+                let result = download.finish();
+                (download, result)
+            }).await;
+            match verified {
+                Ok((download, result)) => APP.lock().unwrap().record_finished(download, result),
+                Err(e) => logger::error!("Verifying a download failed: {:?}", e),
+            }
+        });
+    }
+
+    // Records the outcome of verifying a completed download
+    fn record_finished(&mut self, download: Download, result: std::io::Result<DownloadOutcome>) {
+        let outcome = match result {
+            Ok(DownloadOutcome::Verified) => {
+                logger::info!("File {:?} received, verified and saved successfully", download.filename);
+                DownloadOutcome::Verified
+            }
+            Ok(outcome) => {
+                logger::error!("File {:?} does not match its hash, discarding it", download.filename);
+                outcome
+            }
+            Err(e) => {
+                logger::error!("Error saving file {:?}: {:?}", download.filename, e);
+                DownloadOutcome::Failed(e.to_string())
+            }
+        };
+
+        self.record_outcome(&download.digest.hash, &download.path, &outcome);
+        self.log_download(&download, &outcome);
+        let peers = download.peers();
+        self.finished_downloads.push(FinishedDownload {
+            filename: download.filename,
+            peers,
+            hash: download.digest.hash,
+            size: download.digest.size,
+            outcome,
+            bundle: None,
+        });
+    }
+
+    // Records a finished or abandoned download in the transfer history
+    fn log_download(&mut self, download: &Download, outcome: &DownloadOutcome) {
+        let entry = HistoryEntry {
+            filename: download.filename.clone(),
+            hash: download.digest.hash.clone(),
+            size: download.digest.size,
+            peers: download.peers().iter().map(|peer| self.history_peer(peer)).collect(),
+            direction: TransferDirection::Received,
+            started: download.started,
+            finished: history::unix_now(),
+            outcome: outcome.clone().into(),
+        };
+        self.history.record(entry);
+    }
+
+    /// Records an upload in the transfer history, with how much of the file was sent, unless it
+    /// has been recorded already.
+    pub fn log_upload(&mut self, index: usize) {
+        let Some(upload) = self.uploads.get(index).filter(|upload| !upload.logged) else {
+            return;
+        };
+        let outcome = if upload.is_complete() {
+            TransferOutcome::Completed
+        } else if upload.cancelled {
+            TransferOutcome::Failed("Cancelled".to_string())
+        } else {
+            TransferOutcome::Partial { bytes: upload.sent_bytes() }
+        };
+        let entry = HistoryEntry {
+            filename: upload.filename.clone(),
+            hash: upload.hash.clone(),
+            size: upload.size,
+            peers: vec![self.history_peer(&upload.peer)],
+            direction: TransferDirection::Sent,
+            started: upload.started,
+            finished: history::unix_now(),
+            outcome,
+        };
+        self.history.record(entry);
+        self.uploads[index].logged = true;
+    }
+
+    /// Records every upload which has sent part of its file but has not been recorded yet, such as
+    /// when the app exits.
+    pub fn log_unfinished_uploads(&mut self) {
+        for index in 0..self.uploads.len() {
+            if !self.uploads[index].sent_chunks.is_empty() {
+                self.log_upload(index);
+            }
+        }
+    }
+
+    // Describes a peer with the username it has now, for the transfer history
+    fn history_peer(&self, peer: &PeerId) -> HistoryPeer {
+        HistoryPeer {
+            peer_id: peer.to_string(),
+            username: self.usernames.get(&peer.to_string()).cloned(),
+        }
+    }
+
+    // Records the outcome of a file in every bundle containing it, copying it to the bundle's other
+    // entries with the same contents, and finishes bundles once all of their files have finished
+    pub fn record_outcome(&mut self, hash: &str, path: &Path, outcome: &DownloadOutcome) {
+        for bundle in self.bundles.values_mut() {
+            if bundle.entries_with_hash(hash).next().is_none() {
+                continue;
+            }
+            let destinations: Vec<PathBuf> = bundle.entries_with_hash(hash)
+                .filter_map(|entry| bundle.destinations.get(&entry.path))
+                .filter(|destination| destination.as_path() != path)
+                .cloned()
+                .collect();
+
+            let mut outcome = outcome.clone();
+            if outcome == DownloadOutcome::Verified {
+                for destination in destinations {
+                    let copied = destination.parent().map_or(Ok(()), std::fs::create_dir_all)
+                        .and_then(|_| std::fs::copy(path, &destination));
+                    if let Err(e) = copied {
+                        logger::error!("Error copying {:?} to {:?}: {:?}", path, destination, e);
+                        outcome = DownloadOutcome::Failed(e.to_string());
+                    }
                 }
             }
+            bundle.outcomes.insert(hash.to_string(), outcome);
+        }
+
+        let complete: Vec<String> = self.bundles.iter()
+            .filter(|(_, bundle)| bundle.is_complete())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in complete {
+            let bundle = self.bundles.remove(&key).unwrap();
+            let outcome = bundle.outcome();
+            logger::info!("Bundle {:?} finished: {:?}", bundle.manifest.name, outcome);
+            self.finished_downloads.push(FinishedDownload {
+                size: bundle.manifest.size(),
+                filename: bundle.manifest.name,
+                peers: vec![bundle.peer],
+                hash: bundle.manifest.hash,
+                outcome,
+                bundle: Some(bundle.manifest.requested),
+            });
+        }
+    }
+
+    // Records a file request sent to another peer so its status can be shown
+    pub fn track_request(&mut self, request_id: OutboundRequestId, peer: PeerId, filename: String, kind: RequestKind) {
+        self.sent_requests.push(SentRequest { request_id, peer, filename, kind, status: RequestStatus::Pending });
+    }
+
+    // Updates the status of a file request sent to another peer, if it is being tracked, and
+    // withdraws offers which were not accepted
+    pub fn set_request_status(&mut self, request_id: OutboundRequestId, status: RequestStatus) {
+        let Some(request) = self.sent_requests.iter_mut().find(|request| request.request_id == request_id) else {
+            return;
+        };
+        logger::info!("Request for {:?} to {}: {:?}", request.filename, request.peer, status);
+        request.status = status;
+
+        let refused = !matches!(request.status, RequestStatus::Pending | RequestStatus::Accepted);
+        if let (RequestKind::Offer(digest), true) = (&request.kind, refused) {
+            let peer = request.peer;
+            let hash = digest.hash.clone();
+            self.grants.remove(&(peer, hash.clone()));
+            if let Some(upload) = self.uploads.iter_mut().find(|upload| upload.peer == peer && upload.hash == hash) {
+                upload.cancelled = true;
+            }
+        }
+    }
+
+    // Abandons a download, deleting its partial file unless it is kept to be resumed later, and
+    // records it as cancelled. Returns the peers providing it, so they can be told to stop
+    pub fn cancel_download(&mut self, hash: &str, keep_partial: bool, reason: &str) -> Vec<PeerId> {
+        let Some(download) = self.downloads.remove(hash) else {
+            return Vec::new();
+        };
+        logger::info!("Cancelled download of {:?}: {}", download.filename, reason);
+        let reason = if keep_partial {
+            if let Err(e) = download.keep_cancelled(&self.download_dir) {
+                logger::error!("Error keeping partial file {:?}: {:?}", download.filename, e);
+            }
+            format!("{} (partial kept)", reason)
+        } else {
+            if let Err(e) = download.discard() {
+                logger::error!("Error removing partial file {:?}: {:?}", download.filename, e);
+            }
+            reason.to_string()
+        };
+
+        let outcome = DownloadOutcome::Failed(reason);
+        self.record_outcome(&download.digest.hash, &download.path, &outcome);
+        self.log_download(&download, &outcome);
+        let peers = download.peers();
+        self.finished_downloads.push(FinishedDownload {
+            filename: download.filename,
+            peers: peers.clone(),
+            hash: download.digest.hash,
+            size: download.digest.size,
+            outcome,
+            bundle: None,
+        });
+        peers
+    }
+
+    // Abandons every unfinished file of a bundle, recording the bundle as failed. Returns the hash
+    // of each abandoned download with a peer providing it
+    pub fn cancel_bundle(&mut self, key: &str, keep_partial: bool) -> Vec<(String, PeerId)> {
+        let Some(bundle) = self.bundles.get(key) else {
+            return Vec::new();
+        };
+        logger::info!("Cancelled bundle {:?}", bundle.manifest.name);
+        let mut hashes: Vec<String> = bundle.manifest.entries.iter()
+            .map(|entry| entry.digest.hash.clone())
+            .filter(|hash| !bundle.outcomes.contains_key(hash))
+            .collect();
+        hashes.sort();
+        hashes.dedup();
+
+        let mut cancelled = Vec::new();
+        for hash in hashes {
+            if self.downloads.contains_key(&hash) {
+                let peers = self.cancel_download(&hash, keep_partial, "Cancelled");
+                cancelled.extend(peers.into_iter().map(|peer| (hash.clone(), peer)));
+            } else {
+                self.record_outcome(&hash, Path::new(""), &DownloadOutcome::Failed("Cancelled".to_string()));
+            }
+        }
+        cancelled
+    }
+
+    // Stops serving a file to a peer, so its further chunk requests are refused
+    pub fn cancel_upload(&mut self, index: usize) {
+        let Some(upload) = self.uploads.get_mut(index) else {
+            return;
+        };
+        logger::info!("Cancelled upload of {:?} to {}", upload.filename, upload.peer);
+        upload.cancelled = true;
+        self.grants.remove(&(upload.peer, upload.hash.clone()));
+        self.log_upload(index);
+    }
+
+    // Stops a transfer the peer has cancelled on their side. Uploads of the file to the peer are
+    // cancelled, and the peer stops being a source of its download, which is cancelled if no other
+    // source is left, keeping the partial file if `keep_partials` is set
+    pub fn cancelled_by_peer(&mut self, peer: PeerId, hash: &str) {
+        let index = self.uploads.iter()
+            .position(|upload| upload.peer == peer && upload.hash == hash && !upload.cancelled && !upload.is_complete());
+        if let Some(index) = index {
+            logger::info!("{} cancelled the upload of {:?}", peer, self.uploads[index].filename);
+            self.cancel_upload(index);
+        }
+
+        let Some(download) = self.downloads.get_mut(hash) else {
+            return;
+        };
+        download.remove_source(&peer, false);
+        download.lost_sources.retain(|lost| *lost != peer);
+        if download.sources.is_empty() && download.lost_sources.is_empty() {
+            self.cancel_download(hash, self.keep_partials, "Cancelled by peer");
+        } else {
+            logger::info!("{} stopped providing {:?}", peer, download.filename);
         }
-        self.updating_usernames.store(false, Ordering::SeqCst);
-        logger::info!("{:?}", self.usernames);
     }
 }
 
+#[derive(Debug)]
 pub struct RequestItem {
     pub peer_id: PeerId,
     pub request_string: String,
+    pub kind: RequestKind,
+    /// Compression the requesting peer can decode
+    pub compression: Vec<Compression>,
     pub response_channel: ResponseChannel<Response>,
+    /// When the request arrived, after which it expires unanswered
+    pub received: Instant,
+}
+
+/// What a file request asks for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestKind {
+    /// A single file, named by the request string
+    File,
+    /// Several files or directories as one bundle, by their paths
+    Bundle(Vec<String>),
+    /// A file the peer offers to send, which is downloaded if the offer is accepted
+    Offer(FileDigest),
+    /// A preview of a single file with up to this many lines of text, approved like a request for the file
+    Preview(usize),
+}
+
+/// Answer received so far to a file request sent to another peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestStatus {
+    Pending,
+    Accepted,
+    Rejected(String),
+    /// The peer did not answer before the request expired
+    TimedOut,
+    /// The provider has no file at the requested path or with the requested hash
+    NotFound(String),
+    /// The file or bundle is larger than the provider is willing to send, in bytes
+    TooLarge { size: u64, limit: u64 },
+    /// The request could not be delivered, or the provider failed to read the file
+    Failed(String),
+}
+
+/// A file request sent to another peer.
+pub struct SentRequest {
+    pub request_id: OutboundRequestId,
+    pub peer: PeerId,
+    pub filename: String,
+    pub kind: RequestKind,
+    pub status: RequestStatus,
 }
 
 lazy_static! {
     // Global application state
     pub static ref APP: Arc<Mutex<App>> = Arc::new(Mutex::new(App::new()));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::transfer::{self, digest_reader};
+
+    // Creates an app downloading a file of two chunks, with the first received
+    fn downloading(name: &str) -> (App, String) {
+        let dir = std::env::temp_dir().join(format!("swapbytes-cancel-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = vec![7u8; transfer::CHUNK_SIZE as usize + 10];
+        let digest = digest_reader(data.as_slice()).unwrap();
+        let mut download = Download::new("file.bin".to_string(), digest, PeerId::random(), dir.join("file.bin")).unwrap();
+        download.write_chunk(0, &data[..transfer::CHUNK_SIZE as usize]).unwrap();
+
+        let mut app = App::new();
+        app.download_dir = dir;
+        let hash = download.digest.hash.clone();
+        app.downloads.insert(hash.clone(), download);
+        (app, hash)
+    }
+
+    #[test]
+    fn cancelling_discards_the_partial_file() {
+        let (mut app, hash) = downloading("discard");
+        let part = app.downloads[&hash].part_path();
+        app.cancel_download(&hash, false, "Cancelled");
+        assert!(app.downloads.is_empty() && !part.exists());
+        assert!(transfer::load_partial_downloads(&app.download_dir).is_empty());
+        assert!(transfer::kept_partial(&app.download_dir, &hash).is_none());
+    }
+
+    #[test]
+    fn cancelling_keeps_the_partial_file_without_resuming_it() {
+        let (mut app, hash) = downloading("keep");
+        let part = app.downloads[&hash].part_path();
+        app.cancel_download(&hash, true, "Cancelled");
+        assert!(app.downloads.is_empty() && part.exists());
+        assert!(transfer::load_partial_downloads(&app.download_dir).is_empty());
+
+        // Requesting the file again picks up where it was cancelled
+        let download = transfer::kept_partial(&app.download_dir, &hash).unwrap();
+        assert_eq!(download.received, [(0, transfer::CHUNK_SIZE)]);
+        assert_eq!(download.pending, [1]);
+        assert!(transfer::load_partial_downloads(&app.download_dir).contains_key(&hash));
+        assert!(transfer::kept_partial(&app.download_dir, &hash).is_none());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::chat::{ChatMessage, DeliveryState};
+use crate::state::App;
+use lazy_static::lazy_static;
+use ratatui::{
+    style::{Color, Style},
+    text::{Line, Span},
+};
+use time::{Date, OffsetDateTime, UtcOffset};
+
+lazy_static! {
+    // Offset of the local time zone, which can only be read safely before any other thread starts,
+    // so it is read once at startup by `main`
+    pub static ref LOCAL_OFFSET: UtcOffset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+}
+
+// Converts milliseconds since the Unix epoch to a local date and time
+fn local_time(millis: u64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp((millis / 1000) as i64)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+        .to_offset(*LOCAL_OFFSET)
+}
+
+// Describes how long ago a message was sent, such as `5m ago`
+fn format_relative(millis: u64, now: OffsetDateTime) -> String {
+    let secs = (now - local_time(millis)).whole_seconds().max(0);
+    match secs {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+// Names a day relative to today, or gives its date if it was before yesterday
+fn format_day(date: Date, today: Date) -> String {
+    if date == today {
+        "Today".to_string()
+    } else if today.previous_day() == Some(date) {
+        "Yesterday".to_string()
+    } else {
+        format!("{} {} {} {}", date.weekday(), date.day(), date.month(), date.year())
+    }
+}
+
+// Shows how far a direct message sent by this peer has got, if it was sent this session
+fn delivery_span(app: &App, message: &ChatMessage) -> Option<Span<'static>> {
+    match app.delivery.get(&message.id)? {
+        DeliveryState::Queued => Some(Span::styled(" (queued)", Style::default().fg(Color::Yellow))),
+        DeliveryState::Parked => Some(Span::styled(" (parked)", Style::default().fg(Color::Yellow))),
+        DeliveryState::Sent => Some(Span::styled(" ✓", Style::default().fg(Color::DarkGray))),
+    }
+}
+
+/// Lays out a conversation with the time each message was sent, and a divider before the first
+/// message of each day.
+///
+/// Times are shown in the local time zone, or as how long ago each message was sent if
+/// `relative_times` is set. Direct messages sent this session are marked with whether they are
+/// queued, parked in the DHT or sent.
+pub fn message_lines(app: &App, messages: &[ChatMessage]) -> Vec<Line<'static>> {
+    let now = OffsetDateTime::now_utc().to_offset(*LOCAL_OFFSET);
+    let dim = Style::default().fg(Color::DarkGray);
+    let mut lines = Vec::new();
+    let mut last_day = None;
+
+    for message in messages {
+        let sent = local_time(message.timestamp);
+        if last_day != Some(sent.date()) {
+            last_day = Some(sent.date());
+            lines.push(Line::styled(format!("── {} ──", format_day(sent.date(), now.date())), dim).centered());
+        }
+
+        let time = if app.relative_times {
+            format_relative(message.timestamp, now)
+        } else {
+            format!("{:02}:{:02}", sent.hour(), sent.minute())
+        };
+        let mut spans = vec![
+            Span::styled(format!("[{}] ", time), dim),
+            Span::raw(message.display(&app.sender_name(&message.sender))),
+        ];
+        spans.extend(delivery_span(app, message));
+        lines.push(Line::from(spans));
+    }
+    lines
+}
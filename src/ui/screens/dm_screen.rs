@@ -1,14 +1,20 @@
 use ratatui::{
     style::{Modifier, Style, Color},
     text::{Line, Span},
-    layout::{Constraint, Layout, Position},
+    layout::{Constraint, Flex, Layout, Position, Rect},
     Frame,
-    widgets::{List, ListItem, Paragraph, ListState, Block, Borders},
+    widgets::{List, ListItem, Paragraph, ListState, Block, Borders, Clear},
     crossterm::event::{KeyCode, KeyEvent},
 };
-use crate::network::network::Client;
+use crate::network::network::{Client, REQUEST_EXPIRY};
+use crate::network::bandwidth::{self, SCHEDULER};
+use crate::network::transfer::DownloadOutcome;
 use crate::logger;
+use crate::ui::chat_view;
+use crate::ui::screens::main_screen;
 use crate::APP;
+use crate::state::{RequestItem, RequestKind, RequestStatus};
+use crate::preview::{FilePreview, PreviewContent};
 use std::collections::HashMap;
 use libp2p::{gossipsub, PeerId};
 use std::rc::Rc;
@@ -21,10 +27,18 @@ pub struct DmScreen {
     pub selected_person: usize,
     pub in_sidebar: bool,
     pub in_requests: bool,
+    pub in_files: bool,
+    pub file_state: ListState,
     pub usernames: HashMap<String, String>,
     pub peers: Vec<PeerId>,
 }
 
+impl Default for DmScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DmScreen {
     /// Creates a new `DmScreen` with default state.
     pub fn new() -> Self {
@@ -32,6 +46,8 @@ impl DmScreen {
         people_state.select(Some(0));
         let mut request_state = ListState::default();
         request_state.select(Some(0));
+        let mut file_state = ListState::default();
+        file_state.select(Some(0));
         Self {
             private_messages: HashMap::new(),
             people_state,
@@ -39,14 +55,16 @@ impl DmScreen {
             selected_person: 0,
             in_sidebar: false,
             in_requests: false,
+            in_files: false,
+            file_state,
             usernames: HashMap::new(),
             peers: Vec::new(),
         }
     }
 
-    /// Renders the DM screen including input area, messages, people list, and incoming requests.
+    /// Renders the DM screen including input area, messages, shared files, people list, and incoming requests.
     ///
-    /// Displays the input field, messages with the selected peer, a list of people, and incoming requests.
+    /// Displays the input field, messages with the selected peer, the files they share, a list of people, and incoming requests.
     pub fn render(&mut self, frame: &mut Frame, chunk: Rc<[ratatui::layout::Rect]>, usernames: HashMap<String, String>, peers: Vec<PeerId>) {
         let horizontal = Layout::horizontal([
             Constraint::Length(30),
@@ -58,17 +76,24 @@ impl DmScreen {
             Constraint::Length(3),
             Constraint::Min(1),
         ]);
-        let [input_area, messages_area] = vertical.areas(main_area);
+        let [input_area, conversation_area] = vertical.areas(main_area);
+
+        let horizontal_conversation = Layout::horizontal([
+            Constraint::Min(1),
+            Constraint::Length(40),
+        ]);
+        let [messages_area, files_area] = horizontal_conversation.areas(conversation_area);
         
         let vertical_sidebar = Layout::vertical([
-            Constraint::Percentage(60),
-            Constraint::Percentage(40),
+            Constraint::Percentage(45),
+            Constraint::Percentage(30),
+            Constraint::Percentage(25),
         ]);
 
-        let [peer_area, request_area] = vertical_sidebar.areas(sidebar_area);
+        let [peer_area, request_area, download_area] = vertical_sidebar.areas(sidebar_area);
 
         // Input area
-        let input_style = if self.in_sidebar || self.in_requests {
+        let input_style = if self.in_sidebar || self.in_requests || self.in_files {
             Style::default().fg(Color::DarkGray)
         } else {
             Style::default().fg(Color::Yellow)
@@ -79,7 +104,7 @@ impl DmScreen {
             .block(Block::bordered().title("Input"));
         frame.render_widget(input, input_area);
 
-        if !self.in_sidebar && !self.in_requests {
+        if !self.in_sidebar && !self.in_requests && !self.in_files {
             frame.set_cursor_position(Position {
                 x: input_area.x + app.character_index as u16 + 1,
                 y: input_area.y + 1,
@@ -98,36 +123,90 @@ impl DmScreen {
             .unwrap_or_else(|| "Unknown".to_string());
 
         // Construct the message key using the sorted peer IDs
-        let mut peer_ids = vec![current_user_peer_id.clone(), selected_peer_id.clone()];
+        let mut peer_ids = [current_user_peer_id.clone(), selected_peer_id.clone()];
         peer_ids.sort(); // Sort alphabetically
         let message_key = peer_ids.join("_");
 
-        let private_messages: Vec<Line> = 
+        let private_messages: Vec<Line> = chat_view::message_lines(
+            &app,
             app.private_messages
-            .get(&message_key)
-            .unwrap() // Use an empty vector if no messages are found
-            .iter()
-            .map(|m| Line::from(Span::raw(m)))
-            .collect();
+                .get(&message_key)
+                .map_or(&[], Vec::as_slice), // Use an empty slice if no messages are found
+        );
         let total_messages = private_messages.clone().len() + 3;
         let num_lines =  <u16 as Into<_>>::into(messages_area.height);
         
-        let scroll_position = if total_messages > num_lines {
-            total_messages - num_lines
-        } else {
-            0
-        };
+        let scroll_position = total_messages.saturating_sub(num_lines);
         let private_messages = Paragraph::new(private_messages).block(Block::bordered().title(format!("Messages with {}", selected_username))).scroll((scroll_position as u16, 0));
 
         frame.render_widget(private_messages, messages_area);
 
+        // Results of the current search, otherwise the files shared by the selected peer as last listed by them
+        let (files_title, file_items): (String, Vec<ListItem>) = if let Some(search) = &app.search {
+            let items = search.results.iter().map(|result| {
+                let username = usernames.get(&result.peer.to_string()).cloned().unwrap_or_else(|| "Unknown".to_string());
+                ListItem::new(vec![
+                    Line::from(format!("{} ({})", result.file.name, format_size(result.file.size))),
+                    Line::from(Span::styled(
+                        format!("  {} {}", username, result.file.hash.get(..8).unwrap_or(&result.file.hash)),
+                        Style::default().fg(Color::DarkGray),
+                    )),
+                ])
+            }).collect();
+            (format!("Search: {}", search.terms.join(" ")), items)
+        } else {
+            let shared_files = peers.get(self.selected_person)
+                .and_then(|peer_id| app.catalogues.get(peer_id))
+                .cloned()
+                .unwrap_or_default();
+            let items = shared_files.iter().map(|file| {
+                ListItem::new(vec![
+                    Line::from(format!("{} ({})", file.name, format_size(file.size))),
+                    Line::from(Span::styled(
+                        format!("  {} {}", format_date(file.modified), file.hash.get(..8).unwrap_or(&file.hash)),
+                        Style::default().fg(Color::DarkGray),
+                    )),
+                ])
+            }).collect();
+            (format!("Files shared by {}", selected_username), items)
+        };
+        let file_style = if !self.in_files {
+            Style::default().fg(Color::DarkGray)
+        } else {
+            Style::default().fg(Color::Yellow)
+        };
+        let files = List::new(file_items)
+            .block(Block::default().borders(Borders::ALL).title(files_title))
+            .style(file_style)
+            .highlight_style(if self.in_files {
+                Style::default().add_modifier(Modifier::BOLD).fg(Color::Yellow)
+            } else {
+                Style::default().add_modifier(Modifier::REVERSED)
+            })
+            .highlight_symbol(">>");
+        frame.render_stateful_widget(files, files_area, &mut self.file_state);
+
 
         self.peers = peers.clone();
-        let peer_list: Vec<String> = peers.iter().map(|peer_id| format!("{}", peer_id.to_string())).collect();  
-        let peer_items: Vec<ListItem> = peer_list
+        // Each person is shown with the bytes sent to and received from them, highlighted if they
+        // have given back less than the ratio policy asks for, and marked if they are away
+        let peer_items: Vec<ListItem> = peers
         .iter()
-        .filter_map(|peer| {
-            usernames.get(peer).map(|username| ListItem::new(format!("{}", username)))
+        .filter_map(|peer_id| {
+            usernames.get(&peer_id.to_string()).map(|username| {
+                let balance = app.ledger.balance(peer_id);
+                let color = if app.ratio_policy.is_below(&balance) { Color::Red } else { Color::DarkGray };
+                let ratio = balance.ratio().map(|ratio| format!(" ({:.2})", ratio)).unwrap_or_default();
+                let away = if app.away_peers.contains(peer_id) { " (away)" } else { "" };
+                ListItem::new(Line::from(vec![
+                    Span::raw(username.clone()),
+                    Span::styled(away, Style::default().fg(Color::Yellow)),
+                    Span::styled(
+                        format!("  ↑{} ↓{}{}", format_size(balance.uploaded), format_size(balance.downloaded), ratio),
+                        Style::default().fg(color),
+                    ),
+                ]))
+            })
         })
         .collect();
 
@@ -151,12 +230,28 @@ impl DmScreen {
         let current_requests = &app.current_requests;
         self.usernames = usernames.clone();
         let request_items: Vec<String> = current_requests.iter().map(|request| {
+            // Show how long is left to answer the request before it expires
+            let remaining = REQUEST_EXPIRY.saturating_sub(request.received.elapsed()).as_secs();
+            let expiry = format!("({}:{:02})", remaining / 60, remaining % 60);
+            let description = match &request.kind {
+                RequestKind::Offer(digest) => format!(
+                    "offers {} ({}, {})",
+                    request.request_string,
+                    format_size(digest.size),
+                    digest.hash.get(..8).unwrap_or(&digest.hash),
+                ),
+                RequestKind::Preview(_) => format!("preview of {}", request.request_string),
+                _ => request.request_string.clone(),
+            };
+            let low_ratio = if app.ratio_policy.is_below(&app.ledger.balance(&request.peer_id)) { " [low ratio]" } else { "" };
+            let description = format!("{}{}", description, low_ratio);
+
             // Safely retrieve the username using the peer_id
             match self.usernames.get(&request.peer_id.to_string()) {
-                Some(username) => format!("{} - {}", username, request.request_string),
+                Some(username) => format!("{} - {} {}", username, description, expiry),
                 None => {
                     logger::error!("Username for peer_id {} not found", request.peer_id);
-                    format!("Unknown user - {}", request.request_string)
+                    format!("Unknown user - {} {}", description, expiry)
                 }
             }
         }).collect();
@@ -166,7 +261,7 @@ impl DmScreen {
             Style::default().fg(Color::Yellow)
         };
         let requests = List::new(request_items.into_iter().map(ListItem::new).collect::<Vec<_>>())
-            .block(Block::default().borders(Borders::ALL).title("Incoming Requests"))
+            .block(Block::default().borders(Borders::ALL).title("Incoming Requests (Enter/a/r)"))
             .style(request_style)
             .highlight_style(if self.in_requests {
                 Style::default().add_modifier(Modifier::BOLD).fg(Color::Yellow)
@@ -176,14 +271,88 @@ impl DmScreen {
             .highlight_symbol(">>");
 
         frame.render_stateful_widget(requests, request_area, &mut self.request_state);
+
+        // Answers to sent requests, newest first, then downloads in progress followed by the
+        // verification result of finished downloads
+        let mut download_items: Vec<ListItem> = app.sent_requests.iter().rev()
+            .map(|request| {
+                let (status, color) = match &request.status {
+                    RequestStatus::Pending => ("waiting".to_string(), Color::Yellow),
+                    RequestStatus::Rejected(reason) => (format!("rejected: {}", reason), Color::Red),
+                    RequestStatus::TimedOut => ("timed out".to_string(), Color::Red),
+                    RequestStatus::Accepted => ("accepted".to_string(), Color::Green),
+                    RequestStatus::NotFound(reason) => (format!("not found: {}", reason), Color::Red),
+                    RequestStatus::TooLarge { size, limit } => (
+                        format!("too large: {} over the {} limit", format_size(*size), format_size(*limit)),
+                        Color::Red,
+                    ),
+                    RequestStatus::Failed(reason) => (format!("failed: {}", reason), Color::Red),
+                };
+                let filename = match request.kind {
+                    RequestKind::Offer(_) => format!("offer of {}", request.filename),
+                    RequestKind::Preview(_) => format!("preview of {}", request.filename),
+                    _ => request.filename.clone(),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(format!("{} ", filename)),
+                    Span::styled(status, Style::default().fg(color)),
+                ]))
+            })
+            .collect();
+        download_items.extend(app.downloads.values().map(|download| {
+            let status = if download.is_paused() {
+                "paused".to_string()
+            } else {
+                format!("{}% ({} peers)", download.received_bytes() * 100 / download.digest.size.max(1), download.sources.len())
+            };
+            ListItem::new(format!("{} {}", download.filename, status))
+        }));
+        download_items.extend(app.finished_downloads.iter().rev().map(|finished| {
+            let (status, color) = match &finished.outcome {
+                DownloadOutcome::Verified => ("verified", Color::Green),
+                DownloadOutcome::Corrupted => ("corrupted", Color::Red),
+                DownloadOutcome::Failed(_) => ("failed", Color::Red),
+            };
+            ListItem::new(Line::from(vec![
+                Span::raw(format!("{} ", finished.filename)),
+                Span::styled(status, Style::default().fg(color)),
+            ]))
+        }));
+        let downloads = List::new(download_items)
+            .block(Block::default().borders(Borders::ALL).title("Downloads"))
+            .style(Style::default().fg(Color::DarkGray));
+        frame.render_widget(downloads, download_area);
+
+        if let Some((_, preview)) = &app.preview {
+            render_preview(frame, chunk[1], preview);
+        }
     }
     /// Handles keyboard events for the DM screen.
     ///
     /// Processes key inputs for navigating lists, sending messages or requests, and toggling UI modes.
     /// Returns `Ok(true)` if the Escape key is pressed to exit the application, otherwise `Ok(false)`.
     pub async fn handle_events(&mut self, client: &mut Client, key: KeyEvent) -> Result<bool, std::io::Error> {
+        // An open preview takes every key until it is closed
+        {
+            let mut app = APP.lock().unwrap();
+            if app.preview.is_some() {
+                if matches!(key.code, KeyCode::Esc | KeyCode::Enter | KeyCode::Char('p')) {
+                    app.preview = None;
+                }
+                return Ok(false);
+            }
+        }
+
         match key.code {
             KeyCode::Enter => self.handle_enter(client).await,
+            KeyCode::Char('a') if self.in_requests => self.accept_all_requests(client).await,
+            KeyCode::Char('r') if self.in_requests => self.reject_request(client).await,
+            KeyCode::Char('p') if self.in_files => {
+                if let Some((file, peer_id)) = self.selected_file() {
+                    logger::info!("Sending Preview Request");
+                    client.request_preview(file, peer_id).await;
+                }
+            }
             KeyCode::Char('~') => {
                 self.toggle_ui_modes();
                 
@@ -224,55 +393,183 @@ impl DmScreen {
                 logger::info!("Selected Peer ID: {:?}", peer_id);
             }
             self.in_sidebar = !self.in_sidebar;
+
+            // Fetch the files shared by the newly selected peer
+            if let Some(peer_id) = self.peers.get(self.selected_person) {
+                self.file_state.select(Some(0));
+                APP.lock().unwrap().search = None;
+                client.list_files(*peer_id).await;
+            }
+        } else if self.in_files {
+            if let Some((file, peer_id)) = self.selected_file() {
+                logger::info!("Sending File Request");
+                client.send_request(file, peer_id).await;
+            }
         } else if self.in_requests {
-            let mut app = APP.lock().unwrap();
+            let Some(request) = self.take_selected_request() else {
+                return;
+            };
             logger::info!("Sending File Response");
-            logger::info!("{:?}", self.request_state.clone());
+            client.send_response(request).await;
+        } else {
+            let input = APP.lock().unwrap().input.clone();
+
+            if let Some(query) = input.strip_prefix("!search") {
+                let query = query.trim().to_string();
+                self.file_state.select(Some(0));
+                {
+                    let mut app = APP.lock().unwrap();
+                    app.clear_input();
+                    app.search = None;
+                }
 
-            let index = self.request_state.clone().selected()
-                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No request selected"));
-            let request = app.current_requests.remove(index.expect("Invalid Index"));
-            let channel = request.response_channel;
-            let input = request.request_string.clone();
+                // An empty search returns to browsing the selected peer's files
+                if !query.is_empty() {
+                    client.search(query).await;
+                }
+            } else if input == "!list files" {
+                logger::info!("Sending File List Request");
+                let Some(peer_id) = self.selected_peer("list files") else {
+                    return;
+                };
+                APP.lock().unwrap().clear_input();
+
+                client.list_files(peer_id).await;
+            } else if let Some(paths) = input.strip_prefix("!request files ").or_else(|| input.strip_prefix("!request dir ")) {
+                logger::info!("Sending Bundle Request");
+                let paths: Vec<String> = paths.split_whitespace().map(str::to_string).collect();
+                let Some(peer_id) = self.selected_peer("request files") else {
+                    return;
+                };
+                APP.lock().unwrap().clear_input();
 
-            client.send_response(input.clone(), input.clone(), channel).await;
-            app.input.clear();
-            app.character_index = 0;
-        } else {
-            let mut app = APP.lock().unwrap();
-            let input = app.input.clone();
+                if !paths.is_empty() {
+                    client.request_bundle(paths, peer_id).await;
+                }
+            } else if let Some(path) = input.strip_prefix("!send ") {
+                logger::info!("Sending File Offer");
+                let path = path.trim().to_string();
+                let Some(peer_id) = self.selected_peer("send a file") else {
+                    return;
+                };
+                APP.lock().unwrap().clear_input();
 
-            if !input.is_empty() && !input.starts_with("!request file") {
-                let my_peer_id = app.my_peer_id.as_ref().map_or("No Peer ID".to_string(), |peer_id| peer_id.to_string());
-                logger::info!("peers: {:?}, selected: {:?}", self.peers.clone(), self.selected_person.clone());
-                let peer_id = self.peers[self.selected_person].clone().to_string();
-                let mut peer_ids = vec![my_peer_id, peer_id];
-                peer_ids.sort();
-                let topic = gossipsub::IdentTopic::new(peer_ids.join("_"));
+                if !path.is_empty() {
+                    client.offer_file(path, peer_id).await;
+                }
+            } else if let Some(path) = input.strip_prefix("!preview ") {
+                logger::info!("Sending Preview Request");
+                let path = path.trim().to_string();
+                let Some(peer_id) = self.selected_peer("preview a file") else {
+                    return;
+                };
+                APP.lock().unwrap().clear_input();
 
-                client.submit_message(input.clone(), topic).await;
+                if !path.is_empty() {
+                    client.request_preview(path, peer_id).await;
+                }
+            } else if input.trim() == "!times" {
+                main_screen::toggle_relative_times();
+            } else if let Some(limit) = input.strip_prefix("!limit ") {
+                // Limits apply to transfers straight away, as the scheduler checks them on every chunk
+                APP.lock().unwrap().clear_input();
+                let mut args = limit.split_whitespace();
+                let result = match (args.next(), args.next()) {
+                    (Some(name), Some(rate)) => bandwidth::parse_rate(rate)
+                        .and_then(|rate| SCHEDULER.lock().unwrap().limits.set(name, rate)),
+                    _ => Err("usage: !limit [upload|download|peer-upload|peer-download] [rate|off]".to_string()),
+                };
+                match result {
+                    Ok(()) => logger::info!("Bandwidth limits: {:?}", SCHEDULER.lock().unwrap().limits),
+                    Err(e) => logger::error!("Invalid limit {:?}: {}", limit, e),
+                }
+            } else if !input.is_empty() && !input.starts_with("!request file") {
+                logger::info!("peers: {:?}, selected: {:?}", self.peers.clone(), self.selected_person.clone());
+                let Some(peer_id) = self.selected_peer("send a message") else {
+                    return;
+                };
+                let submitted = {
+                    let mut app = APP.lock().unwrap();
+                    let my_peer_id = app.my_peer_id.as_ref().map_or("No Peer ID".to_string(), |peer_id| peer_id.to_string());
+                    let peer_id = peer_id.to_string();
+                    let mut peer_ids = [my_peer_id, peer_id];
+                    peer_ids.sort();
+                    let topic = peer_ids.join("_");
+                    app.submit_private_message(topic.clone())
+                        .map(|message| (message, gossipsub::IdentTopic::new(topic)))
+                };
 
-                app.submit_private_message(peer_ids.join("_"));
-                app.input.clear();
-                app.character_index = 0;
+                if let Some((message, topic)) = submitted {
+                    client.submit_message(message, topic).await;
+                }
             } else if input.starts_with("!request file") {
                 logger::info!("Sending File Request");
                 let file: Vec<_> = input.split_whitespace().collect();
-                let peer_id = self.peers[self.selected_person].clone();
-                client.send_request(file.get(file.len()-1).expect("").to_string(), peer_id).await;
-                app.input.clear();
-                app.character_index = 0;
+                let Some(peer_id) = self.selected_peer("request a file") else {
+                    return;
+                };
+                APP.lock().unwrap().clear_input();
+
+                client.send_request(file.last().expect("").to_string(), peer_id).await;
             }
         }
     }
 
-    /// Toggles between sidebar and request modes.
+    // Returns the peer selected in the sidebar, logging an error if there is none, such as when no
+    // peers are connected
+    fn selected_peer(&self, action: &str) -> Option<PeerId> {
+        let peer_id = self.peers.get(self.selected_person).copied();
+        if peer_id.is_none() {
+            logger::error!("Cannot {} without a peer selected", action);
+        }
+        peer_id
+    }
+
+    // Removes the selected incoming request from the queue so it can be answered
+    fn take_selected_request(&mut self) -> Option<RequestItem> {
+        let mut app = APP.lock().unwrap();
+        let index = self.request_state.selected().filter(|index| *index < app.current_requests.len())?;
+        let request = app.current_requests.remove(index);
+        if index >= app.current_requests.len() {
+            self.request_state.select(Some(app.current_requests.len().saturating_sub(1)));
+        }
+        Some(request)
+    }
+
+    // Accepts every incoming request at once
+    async fn accept_all_requests(&mut self, client: &mut Client) {
+        let requests = std::mem::take(&mut APP.lock().unwrap().current_requests);
+        logger::info!("Accepting {} requests", requests.len());
+        for request in requests {
+            client.send_response(request).await;
+        }
+        self.request_state.select(Some(0));
+    }
+
+    // Rejects the selected incoming request, sending the text in the input box as the reason
+    async fn reject_request(&mut self, client: &mut Client) {
+        let Some(request) = self.take_selected_request() else {
+            return;
+        };
+        let reason = {
+            let mut app = APP.lock().unwrap();
+            let reason = app.input.trim().to_string();
+            app.clear_input();
+            if reason.is_empty() { "Rejected by user".to_string() } else { reason }
+        };
+        client.reject_request(request.request_string, reason, request.response_channel).await;
+    }
+
+    /// Toggles between sidebar, request and shared file modes.
     fn toggle_ui_modes(&mut self) {
         if self.in_sidebar {
             self.in_sidebar = false;
             self.in_requests = true;
         } else if self.in_requests {
             self.in_requests = false;
+            self.in_files = true;
+        } else if self.in_files {
+            self.in_files = false;
         } else {
             self.in_sidebar = true;
         }
@@ -311,7 +608,7 @@ impl DmScreen {
         }
     }
 
-    /// Handles up arrow input, navigating up in the list of users or requests, depending on the current mode.
+    /// Handles up arrow input, navigating up in the list of users, requests or files, depending on the current mode.
     fn handle_up(&mut self) {
         if self.in_sidebar {
             let user_count = self.usernames.len();
@@ -333,10 +630,20 @@ impl DmScreen {
                 };
                 self.request_state.select(Some(i));
             }
+        } else if self.in_files {
+            let file_count = self.selected_file_count();
+            if file_count > 0 {
+                let i = match self.file_state.selected() {
+                    Some(0) => file_count - 1,
+                    Some(i) => i - 1,
+                    None => 0,
+                };
+                self.file_state.select(Some(i));
+            }
         }
     }
 
-    /// Handles down arrow input, navigating down in the list of users or requests, depending on the current mode.
+    /// Handles down arrow input, navigating down in the list of users, requests or files, depending on the current mode.
     fn handle_down(&mut self) {
         if self.in_sidebar {
             let user_count = self.usernames.len();
@@ -358,9 +665,44 @@ impl DmScreen {
                 };
                 self.request_state.select(Some(i));
             }
+        } else if self.in_files {
+            let file_count = self.selected_file_count();
+            if file_count > 0 {
+                let i = match self.file_state.selected() {
+                    Some(i) if i >= file_count - 1 => 0,
+                    Some(i) => i + 1,
+                    None => 0,
+                };
+                self.file_state.select(Some(i));
+            }
         }
     }
 
+    /// Returns the name of the selected file with the peer sharing it, from the search results or
+    /// the files listed by the selected peer.
+    fn selected_file(&self) -> Option<(String, PeerId)> {
+        let peer_id = self.peers.get(self.selected_person).copied()?;
+        let index = self.file_state.selected()?;
+        let app = APP.lock().unwrap();
+        match &app.search {
+            Some(search) => search.results.get(index).map(|result| (result.file.name.clone(), result.peer)),
+            None => app.catalogues.get(&peer_id)
+                .and_then(|files| files.get(index))
+                .map(|file| (file.name.clone(), peer_id)),
+        }
+    }
+
+    /// Returns the number of search results, or of files listed by the selected peer.
+    fn selected_file_count(&self) -> usize {
+        let app = APP.lock().unwrap();
+        if let Some(search) = &app.search {
+            return search.results.len();
+        }
+        self.peers.get(self.selected_person)
+            .and_then(|peer_id| app.catalogues.get(peer_id).map(|files| files.len()))
+            .unwrap_or(0)
+    }
+
 
     /// Selects a person from the list based on the current selection state.
     ///
@@ -376,3 +718,57 @@ impl DmScreen {
         None
     }
 }
+
+// Draws a preview over the middle of the screen, showing text as it is and images with one
+// half-block character for every two pixels stacked above each other
+fn render_preview(frame: &mut Frame, area: Rect, preview: &FilePreview) {
+    let [area] = Layout::vertical([Constraint::Percentage(80)]).flex(Flex::Center).areas(area);
+    let [area] = Layout::horizontal([Constraint::Percentage(80)]).flex(Flex::Center).areas(area);
+
+    let lines: Vec<Line> = match &preview.content {
+        PreviewContent::Text(lines) => lines.iter().map(|line| Line::from(line.as_str())).collect(),
+        PreviewContent::Image { width, height, pixels }
+            if *width > 0 && pixels.len() == (*width as usize) * (*height as usize) => {
+            let width = *width as usize;
+            let rgb = |pixel: &[u8; 3]| Color::Rgb(pixel[0], pixel[1], pixel[2]);
+            pixels.chunks(width * 2).map(|rows| {
+                let (top, bottom) = rows.split_at(width.min(rows.len()));
+                Line::from(top.iter().enumerate().map(|(x, pixel)| {
+                    let below = bottom.get(x).map_or(Color::Reset, rgb);
+                    Span::styled("▀", Style::default().fg(rgb(pixel)).bg(below))
+                }).collect::<Vec<_>>())
+            }).take((*height as usize).div_ceil(2)).collect()
+        }
+        PreviewContent::Image { .. } | PreviewContent::None => {
+            vec![Line::from(Span::styled("No preview available", Style::default().fg(Color::DarkGray)))]
+        }
+    };
+    let title = format!("Preview: {} ({}, {})", preview.filename, format_size(preview.size), preview.mime);
+    let popup = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(title).title_bottom("Esc/Enter/p to close"));
+    frame.render_widget(Clear, area);
+    frame.render_widget(popup, area);
+}
+
+/// Formats a number of bytes as a human readable size.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Formats seconds since the Unix epoch as a calendar date.
+pub fn format_date(secs: u64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(secs as i64)
+        .map(|datetime| datetime.date().to_string())
+        .unwrap_or_default()
+}
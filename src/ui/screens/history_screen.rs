@@ -0,0 +1,129 @@
+use crate::history::{HistoryEntry, TransferDirection, TransferOutcome};
+use crate::state::APP;
+use crate::ui::screens::dm_screen::format_size;
+use ratatui::{
+    crossterm::event::{KeyCode, KeyEvent},
+    prelude::*,
+    widgets::{Block, Borders, List, ListItem, Paragraph},
+};
+use std::rc::Rc;
+
+// Formats seconds since the Unix epoch as a date and time in UTC
+fn format_time(secs: u64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(secs as i64)
+        .map(|datetime| format!("{} {:02}:{:02}", datetime.date(), datetime.hour(), datetime.minute()))
+        .unwrap_or_default()
+}
+
+// Describes how a transfer ended, with the colour it is shown in
+fn describe_outcome(entry: &HistoryEntry) -> (String, Color) {
+    match &entry.outcome {
+        TransferOutcome::Completed if entry.direction == TransferDirection::Received => ("verified".to_string(), Color::Green),
+        TransferOutcome::Completed => ("sent".to_string(), Color::Green),
+        TransferOutcome::Partial { bytes } => (format!("sent {} of {}", format_size(*bytes), format_size(entry.size)), Color::Yellow),
+        TransferOutcome::Corrupted => ("corrupted".to_string(), Color::Red),
+        TransferOutcome::Failed(reason) => (reason.to_lowercase(), Color::Red),
+    }
+}
+
+/// Renders the transfer history, newest first, filtered by the search terms typed into it.
+///
+/// The selected entry is shown in full below the list, with the whole hash and the IDs of its peers.
+pub fn render(frame: &mut Frame, chunk: Rc<[ratatui::layout::Rect]>) {
+    let mut app = APP.lock().unwrap();
+    let terms: Vec<String> = app.history_query.split_whitespace().map(str::to_string).collect();
+    let entries: Vec<HistoryEntry> = app.history.search(&terms).into_iter().cloned().collect();
+    if app.history_state.selected().is_none_or(|i| i >= entries.len()) {
+        app.history_state.select(if entries.is_empty() { None } else { Some(0) });
+    }
+
+    let [search_area, list_area, details_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(6),
+    ]).areas(chunk[1]);
+
+    let search = Paragraph::new(app.history_query.as_str())
+        .style(Style::default().fg(Color::Yellow))
+        .block(Block::default().borders(Borders::ALL).title("Search by file, hash, peer ID or username"));
+    frame.render_widget(search, search_area);
+
+    let items: Vec<ListItem> = entries.iter().map(|entry| {
+        let (outcome, color) = describe_outcome(entry);
+        let peers = entry.peers.iter()
+            .map(|peer| peer.username.clone().unwrap_or_else(|| {
+                peer.peer_id[peer.peer_id.len().saturating_sub(8)..].to_string()
+            }))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let (arrow, preposition) = match entry.direction {
+            TransferDirection::Sent => ("↑", "to"),
+            TransferDirection::Received => ("↓", "from"),
+        };
+        ListItem::new(Line::from(vec![
+            Span::raw(format!("{}  {} {:<28} {:>10}  {} {:<18} ", format_time(entry.finished), arrow, entry.filename, format_size(entry.size), preposition, peers)),
+            Span::styled(outcome, Style::default().fg(color)),
+        ]))
+    }).collect();
+    let title = format!("Transfer History ({} of {})", entries.len(), app.history.entries.len());
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol(">>");
+    frame.render_stateful_widget(list, list_area, &mut app.history_state);
+
+    let details = app.history_state.selected().and_then(|i| entries.get(i)).map(|entry| {
+        let mut lines = vec![
+            Line::from(format!("Hash: {}", entry.hash)),
+            Line::from(format!("Started {}, finished {} (UTC)", format_time(entry.started), format_time(entry.finished))),
+        ];
+        lines.extend(entry.peers.iter().map(|peer| Line::from(format!(
+            "Peer: {} ({})",
+            peer.peer_id,
+            peer.username.as_deref().unwrap_or("unknown username"),
+        ))));
+        lines
+    }).unwrap_or_default();
+    let details = Paragraph::new(details)
+        .style(Style::default().fg(Color::DarkGray))
+        .block(Block::default().borders(Borders::ALL).title("Details"));
+    frame.render_widget(details, details_area);
+}
+
+/// Handles keyboard events for the transfer history.
+///
+/// Typed characters edit the search terms, and the arrow keys move the selection. Returns `Ok(true)`
+/// if the Escape key is pressed to exit the application, otherwise `Ok(false)`.
+pub async fn handle_events(key: KeyEvent) -> Result<bool, std::io::Error> {
+    let mut app = APP.lock().unwrap();
+    let terms: Vec<String> = app.history_query.split_whitespace().map(str::to_string).collect();
+    let count = app.history.search(&terms).len();
+    let selected = app.history_state.selected().filter(|i| *i < count);
+    match key.code {
+        KeyCode::Up if count > 0 => {
+            let i = match selected {
+                Some(i) => if i == 0 { count - 1 } else { i - 1 },
+                None => 0,
+            };
+            app.history_state.select(Some(i));
+        }
+        KeyCode::Down if count > 0 => {
+            let i = match selected {
+                Some(i) => if i >= count - 1 { 0 } else { i + 1 },
+                None => 0,
+            };
+            app.history_state.select(Some(i));
+        }
+        KeyCode::Char(c) => {
+            app.history_query.push(c);
+            app.history_state.select(Some(0));
+        }
+        KeyCode::Backspace => {
+            app.history_query.pop();
+            app.history_state.select(Some(0));
+        }
+        KeyCode::Esc => return Ok(true),
+        _ => {}
+    }
+    Ok(false)
+}
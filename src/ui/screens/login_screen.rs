@@ -46,21 +46,26 @@ pub fn render(frame: &mut Frame) {
 /// the server as needed. Returns `Ok(true)` if the Enter key triggers
 /// an action, otherwise `Ok(false)`.
 pub async fn handle_events(client: &mut Client, key: KeyEvent) -> Result<bool, std::io::Error> {
-    let mut app = APP.lock().unwrap();
-    match key.code {
-        KeyCode::Enter => {
-            if !app.input.is_empty() && app.connected_peers > 0 {
+    let username = {
+        let mut app = APP.lock().unwrap();
+        match key.code {
+            KeyCode::Enter if !app.input.is_empty() && app.connected_peers > 0 => {
                 app.username = app.input.clone();
-                client.push_username(app.input.clone()).await;
+                let username = app.input.clone();
                 app.clear_input();
-                return Ok(true);
-            }
-        },
-        KeyCode::Char(to_insert) => app.enter_char(to_insert),
-        KeyCode::Backspace => app.delete_char(),
-        KeyCode::Left => app.move_cursor_left(),
-        KeyCode::Right => app.move_cursor_right(),
-        _ => {}
+                Some(username)
+            },
+            KeyCode::Char(to_insert) => { app.enter_char(to_insert); None },
+            KeyCode::Backspace => { app.delete_char(); None },
+            KeyCode::Left => { app.move_cursor_left(); None },
+            KeyCode::Right => { app.move_cursor_right(); None },
+            _ => None,
+        }
+    };
+
+    if let Some(username) = username {
+        client.push_username(username).await;
+        return Ok(true);
     }
     Ok(false)   
 }
@@ -1,5 +1,6 @@
 use crate::state::APP;
 use crate::network::network::Client;
+use crate::ui::chat_view;
 use libp2p::gossipsub;
 use ratatui::{
     crossterm::event::{KeyCode, KeyEvent},
@@ -34,23 +35,16 @@ pub fn render(frame: &mut Frame, chunk: Rc<[ratatui::layout::Rect]>) {
     let current_room_name = app.rooms.get(app.current_room);
 
     let messages: Vec<Line> = if let Some(room_name) = current_room_name {
-    app.public_messages
+    chat_view::message_lines(&app, app.public_messages
         .get(room_name)
-        .unwrap() // Use an empty vector if no messages are found
-        .iter()
-        .map(|m| Line::from(Span::raw(m)))
-        .collect()
+        .unwrap()) // Use an empty vector if no messages are found
     } else {
         Vec::new() // If no room is selected, return an empty vector
     };
     let total_messages = messages.clone().len() + 3;
     let num_lines =  <u16 as Into<_>>::into(messages_area.height);
     
-    let scroll_position = if total_messages > num_lines {
-        total_messages - num_lines
-    } else {
-        0
-    };
+    let scroll_position = total_messages.saturating_sub(num_lines);
     let current_room = &app.rooms[app.current_room];
     let messages = Paragraph::new(messages).block(Block::bordered().title(format!("Current Room: {}", current_room))).scroll((scroll_position as u16, 0));
     frame.render_widget(messages, messages_area);
@@ -62,28 +56,13 @@ pub fn render(frame: &mut Frame, chunk: Rc<[ratatui::layout::Rect]>) {
 /// application state and interacts with the server as needed. Returns
 /// `Ok(true)` if the Escape key is pressed, otherwise `Ok(false)`.
 pub async fn handle_events(client: &mut Client, key: KeyEvent) -> Result<bool, std::io::Error> {
+    if key.code == KeyCode::Enter {
+        handle_enter(client).await;
+        return Ok(false);
+    }
+
     let mut app = APP.lock().unwrap();
     match key.code {
-        KeyCode::Enter => {
-            if app.input.starts_with("!create room ") {
-                let chat_name = &app.input[13..];
-                logger::info!("Attempting to create room: {}", chat_name);
-                let chat_name_len = chat_name.len();
-                if chat_name_len <= 64 && chat_name_len > 0 {
-                    client.create_room(chat_name.to_string()).await;
-                    app.input.clear();
-                    app.character_index = 0;
-                } else {
-                    logger::info!("Failed to add chat room name, name too long")
-                }
-            } else {
-                let message = format!("{}: {}", app.username, app.input);
-                app.submit_public_room_message();
-                let room_name = app.rooms.get(app.current_room).unwrap_or(&"global".to_string()).clone();
-                let topic = gossipsub::IdentTopic::new(room_name);
-                client.submit_message(message, topic).await;
-            }
-        }
         KeyCode::Char(to_insert) => app.enter_char(to_insert),
         KeyCode::Backspace => app.delete_char(),
         KeyCode::Left => app.move_cursor_left(),
@@ -95,3 +74,33 @@ pub async fn handle_events(client: &mut Client, key: KeyEvent) -> Result<bool, s
     }  
     Ok(false)
 }
+
+// Handles the enter key press, either running a command or publishing the input to the current room.
+async fn handle_enter(client: &mut Client) {
+    let input = APP.lock().unwrap().input.clone();
+
+    if let Some(chat_name) = input.strip_prefix("!create room ") {
+        logger::info!("Attempting to create room: {}", chat_name);
+        let chat_name_len = chat_name.len();
+        if chat_name_len <= 64 && chat_name_len > 0 {
+            APP.lock().unwrap().clear_input();
+            client.create_room(chat_name.to_string()).await;
+        } else {
+            logger::info!("Failed to add chat room name, name too long")
+        }
+    } else if input.trim() == "!times" {
+        toggle_relative_times();
+    } else {
+        let submitted = APP.lock().unwrap().submit_public_room_message();
+        if let Some((message, room_name)) = submitted {
+            client.submit_message(message, gossipsub::IdentTopic::new(room_name)).await;
+        }
+    }
+}
+
+/// Switches chat messages between showing the time they were sent and how long ago that was.
+pub fn toggle_relative_times() {
+    let mut app = APP.lock().unwrap();
+    app.relative_times = !app.relative_times;
+    app.clear_input();
+}
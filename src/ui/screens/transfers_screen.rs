@@ -0,0 +1,334 @@
+use crate::network::network::Client;
+use crate::network::bandwidth::{Direction, Scheduler, SCHEDULER};
+use crate::network::compression::Compression;
+use crate::network::transfer::{DownloadOutcome, THROUGHPUT_WINDOW};
+use crate::state::{App, APP};
+use crate::ui::screens::dm_screen::format_size;
+use libp2p::PeerId;
+use ratatui::{
+    crossterm::event::{KeyCode, KeyEvent},
+    prelude::*,
+    widgets::{Block, Borders, Gauge, Paragraph},
+};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Stage a transfer has reached, in the order transfers are listed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum TransferStatus {
+    Active,
+    Queued,
+    Completed,
+    Failed(String),
+}
+
+/// Where a listed transfer is kept in the application state.
+#[derive(Debug, Clone)]
+enum TransferKind {
+    /// A download in progress, by hash
+    Download(String),
+    /// A bundle of downloads in progress, by the hash of its manifest
+    Bundle(String),
+    /// A finished download, by index into `finished_downloads`
+    Finished(usize),
+    /// An upload, by index into `uploads`
+    Upload(usize),
+}
+
+/// A request to send again to retry a transfer.
+#[derive(Debug, Clone)]
+enum Retry {
+    /// A file by its path, for requests refused before the file's hash was known
+    File(String),
+    /// A file by its hash and name
+    Hash(String, String),
+    /// A bundle by the paths it was requested with
+    Bundle(Vec<String>),
+}
+
+/// A single row of the transfer list.
+struct TransferRow {
+    kind: TransferKind,
+    status: TransferStatus,
+    upload: bool,
+    filename: String,
+    peers: Vec<PeerId>,
+    progress: f64,
+    speed: Option<f64>,
+    eta: Option<Duration>,
+}
+
+// Collects every upload and download, active ones first, then queued, completed and failed
+fn transfer_rows(app: &App) -> Vec<TransferRow> {
+    let mut rows = Vec::new();
+
+    for (hash, download) in &app.downloads {
+        rows.push(TransferRow {
+            kind: TransferKind::Download(hash.clone()),
+            status: if download.is_paused() { TransferStatus::Queued } else { TransferStatus::Active },
+            upload: false,
+            filename: download.filename.clone(),
+            peers: download.peers(),
+            progress: download.received_bytes() as f64 / download.digest.size.max(1) as f64,
+            speed: Some(download.throughput.bytes_per_sec()),
+            eta: download.eta(),
+        });
+    }
+
+    for (key, bundle) in &app.bundles {
+        let downloads: Vec<_> = bundle.manifest.entries.iter()
+            .filter_map(|entry| app.downloads.get(&entry.digest.hash))
+            .collect();
+        let paused = downloads.iter().all(|download| download.is_paused());
+        let remaining = bundle.manifest.size() - bundle.received_bytes(&app.downloads);
+        let speed: f64 = downloads.iter().map(|download| download.throughput.bytes_per_sec()).sum();
+        rows.push(TransferRow {
+            kind: TransferKind::Bundle(key.clone()),
+            status: if paused { TransferStatus::Queued } else { TransferStatus::Active },
+            upload: false,
+            filename: format!("{} ({} files)", bundle.manifest.name, bundle.manifest.entries.len()),
+            peers: vec![bundle.peer],
+            progress: bundle.received_bytes(&app.downloads) as f64 / bundle.manifest.size().max(1) as f64,
+            speed: Some(speed),
+            eta: (speed > 0.0).then(|| Duration::from_secs_f64(remaining as f64 / speed)),
+        });
+    }
+
+    for (index, finished) in app.finished_downloads.iter().enumerate() {
+        let status = match &finished.outcome {
+            DownloadOutcome::Verified => TransferStatus::Completed,
+            DownloadOutcome::Corrupted => TransferStatus::Failed("Corrupted".to_string()),
+            DownloadOutcome::Failed(reason) => TransferStatus::Failed(reason.clone()),
+        };
+        rows.push(TransferRow {
+            kind: TransferKind::Finished(index),
+            progress: if status == TransferStatus::Completed { 1.0 } else { 0.0 },
+            status,
+            upload: false,
+            filename: finished.filename.clone(),
+            peers: finished.peers.clone(),
+            speed: None,
+            eta: None,
+        });
+    }
+
+    for (index, upload) in app.uploads.iter().enumerate() {
+        let recently_active = upload.throughput.last_activity()
+            .is_some_and(|time| time.elapsed() <= THROUGHPUT_WINDOW);
+        let status = if upload.cancelled {
+            TransferStatus::Failed("Cancelled".to_string())
+        } else if upload.is_complete() {
+            TransferStatus::Completed
+        } else if recently_active {
+            TransferStatus::Active
+        } else {
+            TransferStatus::Queued
+        };
+        rows.push(TransferRow {
+            kind: TransferKind::Upload(index),
+            upload: true,
+            filename: match upload.compression {
+                Compression::Zstd => format!("{} (zstd)", upload.filename),
+                Compression::None => upload.filename.clone(),
+            },
+            peers: vec![upload.peer],
+            progress: upload.sent_bytes() as f64 / upload.size.max(1) as f64,
+            speed: (status == TransferStatus::Active).then(|| upload.throughput.bytes_per_sec()),
+            eta: (status == TransferStatus::Active)
+                .then(|| upload.throughput.eta(upload.size - upload.sent_bytes()))
+                .flatten(),
+            status,
+        });
+    }
+
+    rows.sort_by(|a, b| a.status.cmp(&b.status).then_with(|| a.filename.cmp(&b.filename)));
+    rows
+}
+
+// Formats a duration as hours, minutes and seconds
+fn format_eta(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+// Describes the bandwidth limits in each direction, and how many chunk requests are waiting on them
+fn format_limits(scheduler: &Scheduler) -> String {
+    let describe = |direction: Direction| match scheduler.limits.get(direction) {
+        (None, None) => "unlimited".to_string(),
+        (Some(global), None) => format!("{}/s", format_size(global)),
+        (None, Some(per_peer)) => format!("{}/s per peer", format_size(per_peer)),
+        (Some(global), Some(per_peer)) => format!("{}/s ({}/s per peer)", format_size(global), format_size(per_peer)),
+    };
+    let mut limits = format!("Limits: ↑ {}  ↓ {}", describe(Direction::Upload), describe(Direction::Download));
+    let queued = scheduler.queued_chunks();
+    if queued > 0 {
+        limits.push_str(&format!("  ({} chunks queued)", queued));
+    }
+    limits
+}
+
+/// Renders every upload and download with its progress, speed and estimated time remaining.
+///
+/// Transfers are grouped by status, with active ones at the top, and the selected transfer is highlighted.
+pub fn render(frame: &mut Frame, chunk: Rc<[ratatui::layout::Rect]>) {
+    let mut app = APP.lock().unwrap();
+    let rows = transfer_rows(&app);
+    if app.transfer_state.selected().is_none_or(|i| i >= rows.len()) {
+        app.transfer_state.select(if rows.is_empty() { None } else { Some(0) });
+    }
+    let selected = app.transfer_state.selected();
+
+    let [list_area, help_area] = Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(chunk[1]);
+    let block = Block::default().borders(Borders::ALL).title("Transfers");
+    let inner = block.inner(list_area);
+    frame.render_widget(block, list_area);
+    let limits = format_limits(&SCHEDULER.lock().unwrap());
+    frame.render_widget(
+        Paragraph::new(format!("Up/Down: select   c: cancel   k: cancel, keep partial   r: retry   {}", limits)).style(Style::default().fg(Color::DarkGray)),
+        help_area,
+    );
+
+    if rows.is_empty() {
+        frame.render_widget(Paragraph::new("No transfers yet").style(Style::default().fg(Color::DarkGray)), inner);
+        return;
+    }
+
+    // Scroll so the selected transfer stays visible
+    let visible = inner.height.max(1) as usize;
+    let offset = selected.unwrap_or(0).saturating_sub(visible - 1);
+
+    for (i, row) in rows.iter().enumerate().skip(offset).take(visible) {
+        let area = Rect { y: inner.y + (i - offset) as u16, height: 1, ..inner };
+        let [text_area, gauge_area] = Layout::horizontal([Constraint::Min(1), Constraint::Length(24)]).areas(area);
+
+        let peers = row.peers.iter()
+            .map(|peer| app.usernames.get(&peer.to_string()).cloned().unwrap_or_else(|| {
+                let id = peer.to_string();
+                id[id.len().saturating_sub(8)..].to_string()
+            }))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let (status, color) = match &row.status {
+            TransferStatus::Active => ("active".to_string(), Color::Yellow),
+            TransferStatus::Queued => ("queued".to_string(), Color::Gray),
+            TransferStatus::Completed => ("completed".to_string(), Color::Green),
+            TransferStatus::Failed(reason) => (reason.to_lowercase(), Color::Red),
+        };
+        let speed = row.speed.map(|speed| format!("{}/s", format_size(speed as u64))).unwrap_or_default();
+        let eta = row.eta.map(format_eta).unwrap_or_default();
+
+        let line = Line::from(vec![
+            Span::raw(if Some(i) == selected { ">>" } else { "  " }),
+            Span::raw(format!("{} {:<28} ", if row.upload { "↑" } else { "↓" }, row.filename)),
+            Span::raw(format!("{:<18} ", peers)),
+            Span::styled(format!("{:<12} ", status), Style::default().fg(color)),
+            Span::raw(format!("{:>11} {:>8} ", speed, eta)),
+        ]);
+        let style = if Some(i) == selected {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default()
+        };
+        frame.render_widget(Paragraph::new(line).style(style), text_area);
+
+        let gauge = Gauge::default()
+            .gauge_style(Style::default().fg(color))
+            .ratio(row.progress.clamp(0.0, 1.0))
+            .label(format!("{:.0}%", row.progress.clamp(0.0, 1.0) * 100.0));
+        frame.render_widget(gauge, gauge_area);
+    }
+}
+
+/// Handles keyboard events for the transfer list.
+///
+/// Processes key inputs to move the selection, cancel the selected transfer, with or without keeping
+/// its partial file, or retry it from the peers it was downloaded from. Returns `Ok(true)` if the Escape key is pressed to exit the
+/// application, otherwise `Ok(false)`.
+pub async fn handle_events(client: &mut Client, key: KeyEvent) -> Result<bool, std::io::Error> {
+    // Requests to send once the application state is unlocked
+    let mut retries: Vec<(Retry, PeerId)> = Vec::new();
+    // Peers to tell about cancelled transfers, with the hash of each file
+    let mut cancellations: Vec<(String, PeerId)> = Vec::new();
+    {
+        let mut app = APP.lock().unwrap();
+        let rows = transfer_rows(&app);
+        let selected = app.transfer_state.selected().filter(|i| *i < rows.len());
+        match key.code {
+            KeyCode::Up if !rows.is_empty() => {
+                let i = match selected {
+                    Some(i) => if i == 0 { rows.len() - 1 } else { i - 1 },
+                    None => 0,
+                };
+                app.transfer_state.select(Some(i));
+            }
+            KeyCode::Down if !rows.is_empty() => {
+                let i = match selected {
+                    Some(i) => if i >= rows.len() - 1 { 0 } else { i + 1 },
+                    None => 0,
+                };
+                app.transfer_state.select(Some(i));
+            }
+            // Cancel the selected transfer, deleting the partial file of a download with `c` or keeping
+            // it to be resumed later with `k`, and tell its peers to stop
+            KeyCode::Char(key @ ('c' | 'k')) => {
+                let keep_partial = key == 'k';
+                match selected.map(|i| &rows[i]) {
+                    Some(TransferRow { kind: TransferKind::Download(hash), .. }) => {
+                        let peers = app.cancel_download(hash, keep_partial, "Cancelled");
+                        cancellations.extend(peers.into_iter().map(|peer| (hash.clone(), peer)));
+                    }
+                    Some(TransferRow { kind: TransferKind::Bundle(key), .. }) => {
+                        cancellations.extend(app.cancel_bundle(key, keep_partial));
+                    }
+                    Some(TransferRow { kind: TransferKind::Upload(index), status: TransferStatus::Active | TransferStatus::Queued, .. }) => {
+                        app.cancel_upload(*index);
+                        let upload = &app.uploads[*index];
+                        cancellations.push((upload.hash.clone(), upload.peer));
+                    }
+                    _ => {}
+                }
+            }
+            KeyCode::Char('r') => match selected.map(|i| &rows[i]) {
+                // Ask the peers of a failed download for the file again
+                Some(TransferRow { kind: TransferKind::Finished(index), status: TransferStatus::Failed(_), .. }) => {
+                    let finished = app.finished_downloads.remove(*index);
+                    let retry = match finished.bundle {
+                        Some(paths) => Retry::Bundle(paths),
+                        None if finished.hash.is_empty() => Retry::File(finished.filename),
+                        None => Retry::Hash(finished.hash, finished.filename),
+                    };
+                    retries.extend(finished.peers.into_iter().map(|peer| (retry.clone(), peer)));
+                }
+                // Ask the disconnected sources of a queued download for the file again
+                Some(TransferRow { kind: TransferKind::Download(hash), status: TransferStatus::Queued, .. }) => {
+                    if let Some(download) = app.downloads.get(hash) {
+                        for peer in &download.lost_sources {
+                            retries.push((Retry::Hash(hash.clone(), download.filename.clone()), *peer));
+                        }
+                    }
+                }
+                _ => {}
+            },
+            KeyCode::Esc => return Ok(true),
+            _ => {}
+        }
+    }
+
+    for (hash, peer) in cancellations {
+        client.cancel_transfer(hash, peer).await;
+    }
+    for (retry, peer) in retries {
+        match retry {
+            Retry::File(filename) => client.send_request(filename, peer).await,
+            Retry::Hash(hash, filename) => client.request_hash(hash, filename, peer).await,
+            Retry::Bundle(paths) => client.request_bundle(paths, peer).await,
+        }
+    }
+    Ok(false)
+}
@@ -1,7 +1,7 @@
 use crate::state::APP;
 use crate::state::Screen;
 use crate::network::network::Client;
-use crate::ui::screens::{main_screen, login_screen, select_room_screen};
+use crate::ui::screens::{main_screen, login_screen, select_room_screen, transfers_screen, history_screen};
 use crate::ui::screens::dm_screen::DmScreen;
 use ratatui::prelude::*;
 
@@ -16,22 +16,26 @@ use ratatui::{
 
 /// Renders the tab bar with the current screen highlighted.
 ///
-/// Displays tabs for Main, Select Room, and DM screens, highlighting the current screen.
+/// Displays tabs for Main, Select Room, DM, Transfers and History screens, highlighting the current screen.
 fn render_tabs(frame: &mut Frame, area: Rect, current_screen: &Screen) {
     let tab_titles = vec![
         Span::raw("Main"),
         Span::raw("Select Room"),
         Span::raw("Direct Messages"),
+        Span::raw("Transfers"),
+        Span::raw("History"),
     ];
     
     let current_index = match current_screen {
         Screen::MainScreen => 0,
         Screen::SelectRoomScreen => 1,
         Screen::DMScreen => 2,
+        Screen::TransfersScreen => 3,
+        Screen::HistoryScreen => 4,
         _ => 0, // Default to MainScreen if LoginScreen or undefined
     };
 
-    let tabs = Tabs::new(tab_titles.into_iter().map(Span::from).collect::<Vec<_>>())
+    let tabs = Tabs::new(tab_titles)
         .select(current_index)
         .highlight_style(Style::default().fg(Color::Yellow));
 
@@ -44,7 +48,9 @@ fn render_tabs(frame: &mut Frame, area: Rect, current_screen: &Screen) {
 pub fn render(frame: &mut Frame, dm_screen: &mut DmScreen) {
     let (current_screen, peers, usernames) = {
         let app = APP.lock().unwrap();
-        (app.current_screen.clone(), app.peers.clone(), app.usernames.clone())    
+        // Peers who are away are listed after the others, so messages can still be left for them
+        let peers = app.peers.iter().chain(app.away_peers.iter().filter(|peer_id| !app.peers.contains(peer_id))).copied().collect();
+        (app.current_screen.clone(), peers, app.usernames.clone())
     };
 
     // Define the layout
@@ -68,6 +74,8 @@ pub fn render(frame: &mut Frame, dm_screen: &mut DmScreen) {
         Screen::MainScreen => main_screen::render(frame, chunks),
         Screen::SelectRoomScreen => select_room_screen::render(frame, chunks),
         Screen::DMScreen => dm_screen.render(frame, chunks, usernames, peers),
+        Screen::TransfersScreen => transfers_screen::render(frame, chunks),
+        Screen::HistoryScreen => history_screen::render(frame, chunks),
     }
 }
 
@@ -89,7 +97,9 @@ pub async fn handle_events(client: &mut Client, dm_screen: &mut DmScreen) -> Res
                     app.current_screen = match current_screen {
                         Screen::MainScreen => Screen::SelectRoomScreen,
                         Screen::SelectRoomScreen => Screen::DMScreen,
-                        Screen::DMScreen => Screen::MainScreen,
+                        Screen::DMScreen => Screen::TransfersScreen,
+                        Screen::TransfersScreen => Screen::HistoryScreen,
+                        Screen::HistoryScreen => Screen::MainScreen,
                         _ => current_screen.clone(), // Stay on the current screen if login screen or unknown
                     };
                 }
@@ -97,7 +107,9 @@ pub async fn handle_events(client: &mut Client, dm_screen: &mut DmScreen) -> Res
                 KeyCode::BackTab => {
                     let mut app = APP.lock().unwrap();
                     app.current_screen = match current_screen {
-                        Screen::MainScreen => Screen::DMScreen,
+                        Screen::MainScreen => Screen::HistoryScreen,
+                        Screen::HistoryScreen => Screen::TransfersScreen,
+                        Screen::TransfersScreen => Screen::DMScreen,
                         Screen::SelectRoomScreen => Screen::MainScreen,
                         Screen::DMScreen => Screen::SelectRoomScreen,
                         _ => current_screen.clone(), // Stay on the current screen if login screen or unknown
@@ -110,6 +122,8 @@ pub async fn handle_events(client: &mut Client, dm_screen: &mut DmScreen) -> Res
                 Screen::MainScreen => main_screen::handle_events(client, key).await,
                 Screen::SelectRoomScreen => select_room_screen::handle_events(key).await,
                 Screen::DMScreen => dm_screen.handle_events(client, key).await,
+                Screen::TransfersScreen => transfers_screen::handle_events(client, key).await,
+                Screen::HistoryScreen => history_screen::handle_events(key).await,
             };
             return result;
         }